@@ -7,42 +7,136 @@ import * as addon from "./load.cjs";
 // which otherwise by default are `any`.
 declare module "./load.cjs" {
   function kanshiNew(opts: KanshiOptions): any;
-  function kanshiWatch(dir: string): Promise<undefined>;
-  function kanshiStart(callback: KanshiCallback): Promise<undefined>;
+  function kanshiWatch(dir: KanshiPath): Promise<WatchHandle>;
+  function kanshiUnwatch(dir: KanshiPath): Promise<undefined>;
+  function kanshiWatches(): WatchHandle[];
+  function kanshiStart(
+    callback: KanshiCallback,
+    statusCallback?: KanshiStatusCallback
+  ): Promise<undefined>;
+  function kanshiReady(): Promise<undefined>;
   function kanshiClose(): boolean;
 }
 
+/// A path, or a Buffer of its raw bytes if it isn't valid UTF-8
+type KanshiPath = string | Buffer;
+
 type KanshiEventTypes =
   | "create"
   | "delete"
   | "modify"
+  | "close_write"
+  | "close_nowrite"
+  | "open"
+  | "access"
+  | "metadata"
   | "moved_to"
   | "moved_from"
   | "move"
+  | "rename"
+  | "overflow"
   | "unknown";
 
 interface KanshiEvent {
   eventType: KanshiEventTypes;
+  /// Increases by one for every event emitted by this Kanshi instance
+  sequence: number;
+  /// Milliseconds since the Unix epoch at which the event was read from the kernel
+  timestamp: number;
+  /// Only set if eventType == "overflow" and the number of lost events is known
+  lost?: number;
+  /// Only set if eventType == "overflow" and a single watch root needs rescanning
+  root?: KanshiPath;
+  /// Only set if eventType == "metadata" and the changed attributes are known
+  attributes?: ("mode" | "owner" | "times" | "xattr")[];
+  /// Id of the WatchHandle whose tree the event happened in
+  watchId?: number;
   target?: {
-    /// Only set if eventType == "moved_from"
-    previousPath?: string;
+    /// Only set if eventType == "rename" or "moved_from"
+    previousPath?: KanshiPath;
     /// Only set if eventType == "moved_to"
-    newPath?: string;
-    path: string;
-    kind: "directory" | "file";
+    newPath?: KanshiPath;
+    path: KanshiPath;
+    /// `path` relative to the root of the watch in `watchId`
+    relativePath?: KanshiPath;
+    /// "unknown" if the item was gone before its type could be looked up, e.g. after a delete
+    kind: "directory" | "file" | "symlink" | "socket" | "fifo" | "block_device" | "char_device" | "unknown";
   };
 }
 
+type KanshiEventClasses =
+  | "create"
+  | "delete"
+  | "modify"
+  | "move"
+  | "close_write"
+  | "close_nowrite"
+  | "open"
+  | "access"
+  | "attrib";
+
 interface KanshiOptions {
   forceEngine?: string
+  eventBufferCapacity?: number
+  backpressure?: "drop_oldest" | "block" | "unbounded"
+  splitRenames?: boolean
+  events?: KanshiEventClasses[]
+  recursive?: boolean
+  maxDepth?: number
+  followSymlinks?: boolean
+  rootPaths?: "as_requested" | "canonical"
+  include?: string[]
+  exclude?: string[]
+  respectIgnoreFiles?: boolean
+  debounceMs?: number
+  debounceMaxWaitMs?: number
+  atomicSavesMs?: number
+}
+
+/// Errors thrown or rejected by Kanshi. File system errors are named
+/// "NotFoundError", "PermissionError", "WatchLimitError" or "FileSystemError".
+interface KanshiError extends Error {
+  /// errno name such as "ENOENT", "EACCES" or "ENOSPC"
+  code?: string;
+  errno?: number;
+  operation?:
+    | "init"
+    | "mark"
+    | "unmark"
+    | "traverse"
+    | "read_events"
+    | "epoll_wait"
+    | "resolve_handle"
+    | "start"
+    | "other";
+  path?: KanshiPath;
+}
+
+interface WatchHandle {
+  id: number;
+  /// Absolute path of the watched directory
+  root: KanshiPath;
+  /// Number of directories marked for this tree
+  directories: number;
+  engine: "fanotify" | "inotify" | "fsevents";
+}
+
+interface KanshiStatus {
+  status: "started" | "warning" | "error" | "stopped";
+  /// Only set if status == "warning" or "error". A "warning" may also carry
+  /// whatever an onEvent() callback threw.
+  error?: KanshiError;
 }
 
 type KanshiCallback = (event: KanshiEvent) => void;
+type KanshiStatusCallback = (status: KanshiStatus) => void;
 
 class Kanshi {
   #kanshi: any;
   #callbacks: Set<KanshiCallback>;
   #masterCallback: KanshiCallback;
+  #statusCallbacks: Set<KanshiStatusCallback>;
+  #masterStatusCallback: KanshiStatusCallback;
 
   constructor(opts?: KanshiOptions) {
     this.#kanshi = addon.kanshiNew(!opts ? {} : opts);
@@ -51,19 +145,45 @@ class Kanshi {
       this.#callbacks.forEach((cb) => cb(event));
     };
     this.#masterCallback.bind(this);
+    this.#statusCallbacks = new Set();
+    this.#masterStatusCallback = (status) => {
+      this.#statusCallbacks.forEach((cb) => cb(status));
+    };
   }
 
-  async watch(dir: string): Promise<undefined> {
+  async watch(dir: KanshiPath): Promise<WatchHandle> {
     return addon.kanshiWatch.call(this.#kanshi, dir);
   }
 
+  async unwatch(dir: KanshiPath): Promise<undefined> {
+    return addon.kanshiUnwatch.call(this.#kanshi, dir);
+  }
+
+  watches(): WatchHandle[] {
+    return addon.kanshiWatches.call(this.#kanshi);
+  }
+
   onEvent(callback: KanshiCallback): () => void {
     this.#callbacks.add(callback);
     return () => this.#callbacks.delete(callback);
   }
 
+  onStatus(callback: KanshiStatusCallback): () => void {
+    this.#statusCallbacks.add(callback);
+    return () => this.#statusCallbacks.delete(callback);
+  }
+
   async start(): Promise<undefined> {
-    return addon.kanshiStart.call(this.#kanshi, this.#masterCallback);
+    return addon.kanshiStart.call(
+      this.#kanshi,
+      this.#masterCallback,
+      this.#masterStatusCallback
+    );
+  }
+
+  /// Resolves once the listener started by start() is reading events
+  async ready(): Promise<undefined> {
+    return addon.kanshiReady.call(this.#kanshi);
   }
 
   close(): boolean {
@@ -72,4 +192,15 @@ class Kanshi {
 }
 
 export default Kanshi;
-export type { KanshiEvent, KanshiOptions, KanshiCallback, KanshiEventTypes };
+export type {
+  KanshiEvent,
+  KanshiOptions,
+  KanshiCallback,
+  KanshiEventTypes,
+  KanshiEventClasses,
+  KanshiError,
+  KanshiPath,
+  KanshiStatus,
+  KanshiStatusCallback,
+  WatchHandle,
+};
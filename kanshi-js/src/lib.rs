@@ -1,14 +1,24 @@
-use std::sync::{Arc, OnceLock};
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, UNIX_EPOCH},
+};
 
 use futures::StreamExt;
 use kanshi::{
-    FileSystemEventType, FileSystemTargetKind, Kanshi, KanshiEngines, KanshiImpl, KanshiOptions,
+    BackpressurePolicy, EventMask, FileSystemEventType, Kanshi, KanshiEngines, KanshiError,
+    KanshiImpl, KanshiOptions, KanshiStatus, RecursionMode, RootPaths, WatchHandle,
 };
-use neon::prelude::*;
+use neon::{prelude::*, types::buffer::TypedArray};
 use tokio::runtime::Runtime;
 
 struct KanshiJS {
     engine: Kanshi,
+    // The debounce window and how long events may be held back at most.
+    debounce: Option<(Duration, Duration)>,
 }
 
 impl Finalize for KanshiJS {
@@ -36,7 +46,7 @@ fn runtime<'a, C: Context<'a>>(cx: &mut C) -> NeonResult<&'static Runtime> {
 impl KanshiJS {
     fn js_new(mut cx: FunctionContext) -> JsResult<JsBox<KanshiJS>> {
         let js_opts = cx.argument::<JsObject>(0)?;
-        let mut kanshi_opts = KanshiOptions { force_engine: None };
+        let mut kanshi_opts = KanshiOptions::default();
 
         if let Ok(Some(force_engine)) = js_opts.get_opt::<JsString, _, _>(&mut cx, "forceEngine") {
             if let Ok(force_engine_str) = force_engine.to_string(&mut cx) {
@@ -52,16 +62,138 @@ impl KanshiJS {
             }
         }
 
+        if let Ok(Some(capacity)) =
+            js_opts.get_opt::<JsNumber, _, _>(&mut cx, "eventBufferCapacity")
+        {
+            let capacity = capacity.value(&mut cx);
+            if capacity < 1.0 || capacity.fract() != 0.0 {
+                return cx.throw_type_error("'eventBufferCapacity' should be a positive integer");
+            }
+            kanshi_opts.event_buffer_capacity = capacity as usize;
+        }
+
+        if let Ok(Some(backpressure)) = js_opts.get_opt::<JsString, _, _>(&mut cx, "backpressure") {
+            let backpressure = BackpressurePolicy::from(&backpressure.value(&mut cx));
+            if let Ok(backpressure) = backpressure {
+                kanshi_opts.backpressure = backpressure;
+            } else {
+                return cx.throw_type_error(backpressure.err().unwrap().to_string());
+            }
+        }
+
+        if let Ok(Some(split_renames)) = js_opts.get_opt::<JsBoolean, _, _>(&mut cx, "splitRenames") {
+            kanshi_opts.split_renames = split_renames.value(&mut cx);
+        }
+
+        if let Ok(Some(events)) = js_opts.get_opt::<JsArray, _, _>(&mut cx, "events") {
+            let mut event_mask = EventMask::empty();
+            for class in events.to_vec(&mut cx)? {
+                let Ok(class) = class.downcast::<JsString, _>(&mut cx) else {
+                    return cx.throw_type_error("'events' should be an Array of Strings");
+                };
+                let class = EventMask::from(&class.value(&mut cx));
+                if let Ok(class) = class {
+                    event_mask |= class;
+                } else {
+                    return cx.throw_type_error(class.err().unwrap().to_string());
+                }
+            }
+            kanshi_opts.event_mask = event_mask;
+        }
+
+        if let Ok(Some(max_depth)) = js_opts.get_opt::<JsNumber, _, _>(&mut cx, "maxDepth") {
+            let max_depth = max_depth.value(&mut cx);
+            if max_depth < 0.0 || max_depth.fract() != 0.0 {
+                return cx.throw_type_error("'maxDepth' should be a non-negative integer");
+            }
+            kanshi_opts.recursion = RecursionMode::MaxDepth(max_depth as usize);
+        }
+
+        if let Ok(Some(recursive)) = js_opts.get_opt::<JsBoolean, _, _>(&mut cx, "recursive") {
+            if !recursive.value(&mut cx) {
+                kanshi_opts.recursion = RecursionMode::NonRecursive;
+            }
+        }
+
+        if let Ok(Some(follow_symlinks)) =
+            js_opts.get_opt::<JsBoolean, _, _>(&mut cx, "followSymlinks")
+        {
+            kanshi_opts.follow_symlinks = follow_symlinks.value(&mut cx);
+        }
+
+        if let Ok(Some(root_paths)) = js_opts.get_opt::<JsString, _, _>(&mut cx, "rootPaths") {
+            let root_paths = RootPaths::from(&root_paths.value(&mut cx));
+            if let Ok(root_paths) = root_paths {
+                kanshi_opts.root_paths = root_paths;
+            } else {
+                return cx.throw_type_error(root_paths.err().unwrap().to_string());
+            }
+        }
+
+        if let Ok(Some(respect_ignore_files)) =
+            js_opts.get_opt::<JsBoolean, _, _>(&mut cx, "respectIgnoreFiles")
+        {
+            kanshi_opts.respect_ignore_files = respect_ignore_files.value(&mut cx);
+        }
+
+        let mut debounce = None;
+        if let Ok(Some(debounce_ms)) = js_opts.get_opt::<JsNumber, _, _>(&mut cx, "debounceMs") {
+            let debounce_ms = debounce_ms.value(&mut cx);
+            if !debounce_ms.is_finite() || debounce_ms < 0.0 {
+                return cx.throw_type_error("'debounceMs' should be a non-negative number");
+            }
+            let debounce_ms = Duration::from_secs_f64(debounce_ms / 1000.0);
+            let mut max_wait = debounce_ms * 10;
+            if let Ok(Some(max_wait_ms)) =
+                js_opts.get_opt::<JsNumber, _, _>(&mut cx, "debounceMaxWaitMs")
+            {
+                let max_wait_ms = max_wait_ms.value(&mut cx);
+                if !max_wait_ms.is_finite() || max_wait_ms < 0.0 {
+                    return cx
+                        .throw_type_error("'debounceMaxWaitMs' should be a non-negative number");
+                }
+                max_wait = Duration::from_secs_f64(max_wait_ms / 1000.0);
+            }
+            debounce = Some((debounce_ms, max_wait));
+        }
+
+        if let Ok(Some(atomic_saves_ms)) =
+            js_opts.get_opt::<JsNumber, _, _>(&mut cx, "atomicSavesMs")
+        {
+            let atomic_saves_ms = atomic_saves_ms.value(&mut cx);
+            if !atomic_saves_ms.is_finite() || atomic_saves_ms < 0.0 {
+                return cx.throw_type_error("'atomicSavesMs' should be a non-negative number");
+            }
+            kanshi_opts.atomic_saves = Some(Duration::from_secs_f64(atomic_saves_ms / 1000.0));
+        }
+
+        for (name, globs) in [
+            ("include", &mut kanshi_opts.include),
+            ("exclude", &mut kanshi_opts.exclude),
+        ] {
+            if let Ok(Some(patterns)) = js_opts.get_opt::<JsArray, _, _>(&mut cx, name) {
+                for pattern in patterns.to_vec(&mut cx)? {
+                    let Ok(pattern) = pattern.downcast::<JsString, _>(&mut cx) else {
+                        return cx.throw_type_error(format!("'{name}' should be an Array of Strings"));
+                    };
+                    globs.push(pattern.value(&mut cx));
+                }
+            }
+        }
+
         let kanshi = Kanshi::new(kanshi_opts);
         if let Ok(kanshi) = kanshi {
-            Ok(cx.boxed(KanshiJS { engine: kanshi }))
+            Ok(cx.boxed(KanshiJS {
+                engine: kanshi,
+                debounce,
+            }))
         } else {
-            cx.throw_error(kanshi.err().unwrap().to_string())
+            throw_kanshi_error(&mut cx, kanshi.err().unwrap())
         }
     }
 
     fn js_watch(mut cx: FunctionContext) -> JsResult<JsPromise> {
-        let dir = cx.argument::<JsString>(0)?.value(&mut cx);
+        let dir = path_argument(&mut cx, 0)?;
         let kanshi = (cx.this::<JsBox<KanshiJS>>()?).engine.clone();
         let (deferred, promise) = cx.promise();
 
@@ -71,9 +203,29 @@ impl KanshiJS {
         rt.spawn(async move {
             let watch_ret = kanshi.watch(&dir).await;
 
+            deferred.settle_with(&channel, move |mut cx| match watch_ret {
+                Ok(handle) => watch_handle_to_js(&mut cx, handle),
+                Err(e) => throw_kanshi_error(&mut cx, e),
+            });
+        });
+
+        Ok(promise)
+    }
+
+    fn js_unwatch(mut cx: FunctionContext) -> JsResult<JsPromise> {
+        let dir = path_argument(&mut cx, 0)?;
+        let kanshi = (cx.this::<JsBox<KanshiJS>>()?).engine.clone();
+        let (deferred, promise) = cx.promise();
+
+        let rt = runtime(&mut cx)?;
+        let channel = cx.channel();
+
+        rt.spawn(async move {
+            let unwatch_ret = kanshi.unwatch(&dir).await;
+
             deferred.settle_with(&channel, move |mut cx| {
-                if let Err(e) = watch_ret {
-                    cx.throw_error(e.to_string())
+                if let Err(e) = unwatch_ret {
+                    throw_kanshi_error(&mut cx, e)
                 } else {
                     Ok(cx.undefined())
                 }
@@ -83,9 +235,28 @@ impl KanshiJS {
         Ok(promise)
     }
 
+    fn js_watches(mut cx: FunctionContext) -> JsResult<JsArray> {
+        let handles = (cx.this::<JsBox<KanshiJS>>()?).engine.watches();
+        let js_array = cx.empty_array();
+
+        for (idx, handle) in handles.into_iter().enumerate() {
+            let js_handle = watch_handle_to_js(&mut cx, handle)?;
+            js_array.set(&mut cx, idx as u32, js_handle)?;
+        }
+
+        Ok(js_array)
+    }
+
     fn js_start(mut cx: FunctionContext) -> JsResult<JsPromise> {
         let kanshi_js = cx.this::<JsBox<KanshiJS>>()?;
         let js_callback = Arc::new(cx.argument::<JsFunction>(0)?.root(&mut cx));
+        let js_status_callback = match cx.argument_opt(1) {
+            Some(arg) if !arg.is_a::<JsUndefined, _>(&mut cx) => {
+                let js_status_callback = arg.downcast_or_throw::<JsFunction, _>(&mut cx)?;
+                Some(Arc::new(js_status_callback.root(&mut cx)))
+            }
+            _ => None,
+        };
 
         let channel = cx.channel();
         let sub_thread_channel = cx.channel();
@@ -95,12 +266,58 @@ impl KanshiJS {
         let kanshi = kanshi_js.engine.clone();
 
         // Create a single stream to use for all callbacks.
-        let mut stream = kanshi.get_events_stream();
+        let mut stream = match kanshi_js.debounce {
+            Some((debounce, max_wait)) => kanshi.get_debounced_events_stream(debounce, max_wait),
+            None => kanshi.get_events_stream(),
+        };
+        if let Some(js_status_callback) = js_status_callback.clone() {
+            let mut status_stream = kanshi.get_status_stream();
+            let status_channel = cx.channel();
+
+            rt.spawn(async move {
+                while let Some(status) = status_stream.next().await {
+                    let callback = js_status_callback.clone();
+                    // An exception thrown by the callback already reaches JS as uncaught.
+                    let _ = status_channel
+                        .send(move |mut cx| {
+                            let this = cx.undefined();
+                            let js_status = JsObject::new(&mut cx);
+
+                            let status_str = match status {
+                                KanshiStatus::Started => "started",
+                                KanshiStatus::Warning(e) => {
+                                    let js_error = kanshi_error_to_js(&mut cx, e)?;
+                                    js_status.set(&mut cx, "error", js_error)?;
+                                    "warning"
+                                }
+                                KanshiStatus::Error(e) => {
+                                    let js_error = kanshi_error_to_js(&mut cx, e)?;
+                                    js_status.set(&mut cx, "error", js_error)?;
+                                    "error"
+                                }
+                                KanshiStatus::Stopped => "stopped",
+                            };
+                            let js_string = cx.string(status_str);
+                            js_status.set(&mut cx, "status", js_string)?;
+
+                            let js_status_as_value = js_status.as_value(&mut cx);
+                            callback
+                                .to_inner(&mut cx)
+                                .call(&mut cx, this, [js_status_as_value])?;
+
+                            Ok(())
+                        })
+                        .await;
+                }
+            });
+        }
 
         rt.spawn(async move {
             while let Some(event) = stream.next().await {
                 let callback = js_callback.clone();
-                let handle = sub_thread_channel
+                let status_callback = js_status_callback.clone();
+                // Whatever the callbacks threw already reached JS.
+                let _ = sub_thread_channel
                     .send(move |mut cx| {
                         // let cbs = callbacks.as_ref();
                         let this = cx.undefined();
@@ -111,13 +328,38 @@ impl KanshiJS {
                         let event_type = &event.event_type;
                         let event_type_str = match event_type {
                             FileSystemEventType::MovedFrom(path) => {
-                                let js_string = JsString::new(&mut cx, path.to_str().unwrap());
-                                js_event_target.set(&mut cx, "previousPath", js_string)?;
+                                let js_path = path_to_js(&mut cx, path)?;
+                                js_event_target.set(&mut cx, "previousPath", js_path)?;
                                 event.event_type.to_string()
                             }
                             FileSystemEventType::MovedTo(path) => {
-                                let js_string = JsString::new(&mut cx, path.to_str().unwrap());
-                                js_event_target.set(&mut cx, "nextPath", js_string)?;
+                                let js_path = path_to_js(&mut cx, path)?;
+                                js_event_target.set(&mut cx, "newPath", js_path)?;
+                                event.event_type.to_string()
+                            }
+                            FileSystemEventType::Rename { from, .. } => {
+                                let js_path = path_to_js(&mut cx, from)?;
+                                js_event_target.set(&mut cx, "previousPath", js_path)?;
+                                event.event_type.to_string()
+                            }
+                            FileSystemEventType::Overflow { lost, root } => {
+                                if let Some(lost) = lost {
+                                    let js_number = cx.number(*lost as f64);
+                                    js_event.set(&mut cx, "lost", js_number)?;
+                                }
+                                if let Some(root) = root {
+                                    let js_path = path_to_js(&mut cx, root)?;
+                                    js_event.set(&mut cx, "root", js_path)?;
+                                }
+                                event.event_type.to_string()
+                            }
+                            FileSystemEventType::Metadata(Some(changes)) => {
+                                let js_array = JsArray::new(&mut cx, changes.iter().count());
+                                for (idx, (name, _)) in changes.iter_names().enumerate() {
+                                    let js_string = JsString::new(&mut cx, name.to_lowercase());
+                                    js_array.set(&mut cx, idx as u32, js_string)?;
+                                }
+                                js_event.set(&mut cx, "attributes", js_array)?;
                                 event.event_type.to_string()
                             }
                             x => x.to_string(),
@@ -126,16 +368,34 @@ impl KanshiJS {
                         let js_string = JsString::new(&mut cx, event_type_str);
                         js_event.set(&mut cx, "eventType", js_string)?;
 
+                        let js_number = cx.number(event.sequence as f64);
+                        js_event.set(&mut cx, "sequence", js_number)?;
+
+                        // Milliseconds since the Unix epoch, like `Date.now()`.
+                        let timestamp = event
+                            .timestamp
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs_f64()
+                            * 1000.0;
+                        let js_number = cx.number(timestamp);
+                        js_event.set(&mut cx, "timestamp", js_number)?;
+
+                        if let Some(watch_id) = event.watch_id {
+                            let js_number = cx.number(watch_id as f64);
+                            js_event.set(&mut cx, "watchId", js_number)?;
+                        }
+
                         if let Some(target) = event.target {
-                            let js_string = JsString::new(&mut cx, target.path.to_str().unwrap());
-                            js_event_target.set(&mut cx, "path", js_string)?;
+                            let js_path = path_to_js(&mut cx, &target.path)?;
+                            js_event_target.set(&mut cx, "path", js_path)?;
 
-                            let kind = match target.kind {
-                                FileSystemTargetKind::Directory => {
-                                    JsString::new(&mut cx, "directory")
-                                }
-                                FileSystemTargetKind::File => JsString::new(&mut cx, "file"),
-                            };
+                            if let Some(relative_path) = &target.relative_path {
+                                let js_path = path_to_js(&mut cx, relative_path)?;
+                                js_event_target.set(&mut cx, "relativePath", js_path)?;
+                            }
+
+                            let kind = JsString::new(&mut cx, target.kind.to_string());
                             js_event_target.set(&mut cx, "kind", kind)?;
                         }
 
@@ -143,16 +403,29 @@ impl KanshiJS {
 
                         let js_event_as_value = js_event.as_value(&mut cx);
 
-                        callback
-                            .to_inner(&mut cx)
-                            .call(&mut cx, this, [js_event_as_value])?;
-
-                        Ok(())
+                        let callback = callback.to_inner(&mut cx);
+                        let thrown =
+                            cx.try_catch(|cx| callback.call(cx, this, [js_event_as_value]));
+                        match (thrown, status_callback) {
+                            (Ok(_), _) => Ok(()),
+                            // Passed on as a warning, so that it doesn't go uncaught.
+                            (Err(error), Some(status_callback)) => {
+                                let js_status = JsObject::new(&mut cx);
+                                let js_string = cx.string("warning");
+                                js_status.set(&mut cx, "status", js_string)?;
+                                js_status.set(&mut cx, "error", error)?;
+
+                                let js_status_as_value = js_status.as_value(&mut cx);
+                                status_callback.to_inner(&mut cx).call(
+                                    &mut cx,
+                                    this,
+                                    [js_status_as_value],
+                                )?;
+                                Ok(())
+                            }
+                            (Err(error), None) => cx.throw(error),
+                        }
                     }).await;
-
-                if let Err(e) = handle {
-                    println!("{:?}", e);
-                }
             }
         });
 
@@ -160,7 +433,7 @@ impl KanshiJS {
             let ret = kanshi.start().await;
             deferred.settle_with(&channel, move |mut cx| {
                 if let Err(e) = ret {
-                    cx.throw_error(e.to_string())
+                    throw_kanshi_error(&mut cx, e)
                 } else {
                     Ok(cx.undefined())
                 }
@@ -170,6 +443,28 @@ impl KanshiJS {
         Ok(promise)
     }
 
+    fn js_ready(mut cx: FunctionContext) -> JsResult<JsPromise> {
+        let kanshi = (cx.this::<JsBox<KanshiJS>>()?).engine.clone();
+        let (deferred, promise) = cx.promise();
+
+        let rt = runtime(&mut cx)?;
+        let channel = cx.channel();
+
+        rt.spawn(async move {
+            let ready_ret = kanshi.ready().await;
+
+            deferred.settle_with(&channel, move |mut cx| {
+                if let Err(e) = ready_ret {
+                    throw_kanshi_error(&mut cx, e)
+                } else {
+                    Ok(cx.undefined())
+                }
+            });
+        });
+
+        Ok(promise)
+    }
+
     fn js_close(mut cx: FunctionContext) -> JsResult<JsBoolean> {
         let kanshi_js = cx.this::<JsBox<KanshiJS>>()?;
         let ret = kanshi_js.engine.close();
@@ -177,11 +472,117 @@ impl KanshiJS {
         Ok(cx.boolean(ret))
     }
 }
+/// Reads a path argument, which is a String or, for names that aren't valid UTF-8,
+/// a Buffer of the raw bytes.
+fn path_argument(cx: &mut FunctionContext, i: usize) -> NeonResult<PathBuf> {
+    let arg = cx.argument::<JsValue>(i)?;
+    if let Ok(buffer) = arg.downcast::<JsBuffer, _>(cx) {
+        Ok(path_from_bytes(buffer.as_slice(&*cx)))
+    } else if let Ok(string) = arg.downcast::<JsString, _>(cx) {
+        Ok(PathBuf::from(string.value(cx)))
+    } else {
+        cx.throw_type_error("Paths should be a String or a Buffer")
+    }
+}
+
+/// Converts a path to a JS String, or to a Buffer of its raw bytes if it isn't valid
+/// UTF-8, like `fs.readdir()` with `encoding: "buffer"`.
+fn path_to_js<'a, C: Context<'a>>(cx: &mut C, path: &Path) -> JsResult<'a, JsValue> {
+    match path.to_str() {
+        Some(path) => Ok(cx.string(path).upcast()),
+        #[cfg(unix)]
+        None => Ok(JsBuffer::from_slice(cx, path.as_os_str().as_bytes())?.upcast()),
+        // Elsewhere names aren't raw bytes, so this is as close as a String gets.
+        #[cfg(not(unix))]
+        None => Ok(cx.string(path.to_string_lossy()).upcast()),
+    }
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+/// Elsewhere names aren't raw bytes, so the Buffer is read as UTF-8.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Converts a `WatchHandle` to a plain JS object.
+fn watch_handle_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    handle: WatchHandle,
+) -> JsResult<'a, JsObject> {
+    let js_handle = cx.empty_object();
+
+    let js_number = cx.number(handle.id as f64);
+    js_handle.set(cx, "id", js_number)?;
+    let js_path = path_to_js(cx, &handle.root)?;
+    js_handle.set(cx, "root", js_path)?;
+    let js_number = cx.number(handle.directories as f64);
+    js_handle.set(cx, "directories", js_number)?;
+    let js_string = cx.string(handle.engine.to_string());
+    js_handle.set(cx, "engine", js_string)?;
+
+    Ok(js_handle)
+}
+
+/// Throws a `KanshiError` as a JS `Error`.
+fn throw_kanshi_error<'a, C: Context<'a>, T>(cx: &mut C, err: KanshiError) -> NeonResult<T> {
+    let js_error = kanshi_error_to_js(cx, err)?;
+    cx.throw(js_error)
+}
+
+/// Converts a `KanshiError` to a JS `Error`. File system errors get a `name`
+/// matching their kind, plus Node style `code` and `errno` properties.
+fn kanshi_error_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    err: KanshiError,
+) -> JsResult<'a, JsError> {
+    let js_error = JsError::error(cx, err.to_string())?;
+
+    if let KanshiError::FileSystemError(e) = &err {
+        let name = match e.kind {
+            io::ErrorKind::NotFound => "NotFoundError",
+            io::ErrorKind::PermissionDenied => "PermissionError",
+            io::ErrorKind::StorageFull => "WatchLimitError",
+            _ => "FileSystemError",
+        };
+        let js_string = cx.string(name);
+        js_error.set(cx, "name", js_string)?;
+
+        #[cfg(unix)]
+        if let Some(errno) = e.errno() {
+            let js_string = cx.string(format!("{errno:?}"));
+            js_error.set(cx, "code", js_string)?;
+        }
+
+        if let Some(errno) = e.raw_os_error {
+            let js_number = cx.number(errno);
+            js_error.set(cx, "errno", js_number)?;
+        }
+
+        let js_string = cx.string(e.operation.to_string());
+        js_error.set(cx, "operation", js_string)?;
+
+        if let Some(path) = &e.path {
+            let js_path = path_to_js(cx, path)?;
+            js_error.set(cx, "path", js_path)?;
+        }
+    }
+
+    Ok(js_error)
+}
+
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("kanshiNew", KanshiJS::js_new)?;
     cx.export_function("kanshiWatch", KanshiJS::js_watch)?;
+    cx.export_function("kanshiUnwatch", KanshiJS::js_unwatch)?;
+    cx.export_function("kanshiWatches", KanshiJS::js_watches)?;
     cx.export_function("kanshiStart", KanshiJS::js_start)?;
+    cx.export_function("kanshiReady", KanshiJS::js_ready)?;
     cx.export_function("kanshiClose", KanshiJS::js_close)?;
     Ok(())
 }
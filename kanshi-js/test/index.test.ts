@@ -49,7 +49,7 @@ describe("#kanshiSuccess", () => {
           let hasDelete = false;
           kan.onEvent((event: KanshiEvent) => {
             if (event.eventType === "create") hasCreate = true;
-            if (event.eventType === "moved_from") hasMove = true;
+            if (event.eventType === "rename") hasMove = true;
             if (event.eventType === "delete") hasDelete = true;
             if (hasCreate && hasDelete && hasMove) resolve();
 
@@ -1,18 +1,43 @@
+import os
 from typing import Callable
 from ._kanshipy import KanshiPy as _Kanshipy
-from ._kanshipy import KanshiEvent, KanshiEventTarget
+from ._kanshipy import KanshiEvent, KanshiEventTarget, KanshiStatus, WatchHandle, WatchLimitError
 
 class KanshiPy:
   
   _kanshi: _Kanshipy
   _callbacks: set[Callable[[KanshiEvent], None]]
+  _status_callbacks: set[Callable[[KanshiStatus], None]]
   
-  def __init__(self, force_engine: str | None = None):
-    self._kanshi = _Kanshipy.new(force_engine=force_engine if force_engine else "")
+  def __init__(self, force_engine: str | None = None, event_buffer_capacity: int = 32, backpressure: str = "drop_oldest", split_renames: bool = False, events: list[str] | None = None, recursive: bool = True, max_depth: int | None = None, follow_symlinks: bool = False, root_paths: str = "as_requested", include: list[str] | None = None, exclude: list[str] | None = None, respect_ignore_files: bool = False, debounce: float | None = None, debounce_max_wait: float | None = None, atomic_saves: float | None = None):
+    self._kanshi = _Kanshipy.new(
+      force_engine=force_engine if force_engine else "",
+      event_buffer_capacity=event_buffer_capacity,
+      backpressure=backpressure,
+      split_renames=split_renames,
+      events=events,
+      recursive=recursive,
+      max_depth=max_depth,
+      follow_symlinks=follow_symlinks,
+      root_paths=root_paths,
+      include=include,
+      exclude=exclude,
+      respect_ignore_files=respect_ignore_files,
+      debounce=debounce,
+      debounce_max_wait=debounce_max_wait,
+      atomic_saves=atomic_saves,
+    )
     self._callbacks = set()
+    self._status_callbacks = set()
     
-  def watch(self, dir: str):
-    self._kanshi.watch(dir)
+  def watch(self, dir: str | bytes | os.PathLike) -> WatchHandle:
+    return self._kanshi.watch(os.fsdecode(dir))
+    
+  def unwatch(self, dir: str | bytes | os.PathLike):
+    self._kanshi.unwatch(os.fsdecode(dir))
+    
+  def watches(self) -> list[WatchHandle]:
+    return self._kanshi.watches()
     
   def subscribe(self, callback: Callable[[KanshiEvent], None]):
     self._callbacks.add(callback)
@@ -21,8 +46,21 @@ class KanshiPy:
     for callback in self._callbacks:
       callback(event)
   
+  def subscribe_status(self, callback: Callable[[KanshiStatus], None]):
+    self._status_callbacks.add(callback)
+  
+  def _master_status_callback(self, status: KanshiStatus):
+    for callback in self._status_callbacks:
+      callback(status)
+  
   def start(self):
-    self._kanshi.start(self._master_callback)
+    self._kanshi.start(self._master_callback, self._master_status_callback)
+  
+  def ready(self, timeout: float | None = None) -> bool:
+    return self._kanshi.ready(timeout)
+  
+  async def ready_async(self):
+    await self._kanshi.ready_async()
   
   def close(self):
     self._kanshi.close()
\ No newline at end of file
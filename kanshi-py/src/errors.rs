@@ -0,0 +1,37 @@
+use std::io;
+
+use kanshi::KanshiError;
+use pyo3::{
+    create_exception,
+    exceptions::{PyFileNotFoundError, PyOSError, PyPermissionError, PyRuntimeError, PyValueError},
+    PyErr,
+};
+
+create_exception!(
+    _kanshipy,
+    WatchLimitError,
+    PyOSError,
+    "The kernel's limit on watches or marks has been reached (ENOSPC)."
+);
+
+/// Maps file system errors onto the matching `OSError` subclass, with `errno`
+/// and `filename` set, so Python callers can catch them by type.
+pub fn to_py_err(err: KanshiError) -> PyErr {
+    match err {
+        KanshiError::FileSystemError(e) => {
+            let message = e.to_string();
+            let Some(errno) = e.raw_os_error else {
+                return PyOSError::new_err(message);
+            };
+            let args = (errno, message, e.path);
+            match e.kind {
+                io::ErrorKind::NotFound => PyFileNotFoundError::new_err(args),
+                io::ErrorKind::PermissionDenied => PyPermissionError::new_err(args),
+                io::ErrorKind::StorageFull => WatchLimitError::new_err(args),
+                _ => PyOSError::new_err(args),
+            }
+        }
+        KanshiError::InvalidParameter(_) => PyValueError::new_err(err.to_string()),
+        _ => PyRuntimeError::new_err(err.to_string()),
+    }
+}
@@ -1,18 +1,29 @@
+mod errors;
 mod runtime;
 
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
+};
+
 use futures::StreamExt;
 use kanshi::{
-    FileSystemEventType, FileSystemTargetKind, Kanshi, KanshiEngines, KanshiImpl, KanshiOptions,
+    BackpressurePolicy, EventMask, FileSystemEventType, Kanshi, KanshiEngines, KanshiImpl,
+    KanshiOptions, RecursionMode, RootPaths, DEFAULT_EVENT_BUFFER_CAPACITY,
 };
 use pyo3::{
-    exceptions::{PyAttributeError, PyIOError, PyRuntimeError},
+    exceptions::{PyAttributeError, PyBaseException, PyRuntimeError, PyValueError},
     prelude::*,
 };
+use errors::{to_py_err, WatchLimitError};
 use runtime::get_runtime;
 
 #[pyclass(unsendable)]
 pub struct KanshiPy {
     kanshi: Kanshi,
+    // The debounce window and how long events may be held back at most.
+    debounce: Option<(Duration, Duration)>,
 }
 
 #[pyclass(get_all)]
@@ -20,36 +31,135 @@ pub struct KanshiPy {
 pub struct KanshiEvent {
     pub event_type: String,
     pub target: Option<KanshiEventTarget>,
+    pub sequence: u64,
+    pub timestamp: f64,
+    pub lost: Option<u64>,
+    pub root: Option<OsString>,
+    pub attributes: Option<Vec<String>>,
+    pub watch_id: Option<u64>,
+}
+
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct WatchHandle {
+    pub id: u64,
+    pub root: OsString,
+    pub directories: usize,
+    pub engine: String,
+}
+
+impl From<kanshi::WatchHandle> for WatchHandle {
+    fn from(handle: kanshi::WatchHandle) -> Self {
+        WatchHandle {
+            id: handle.id,
+            root: handle.root.into_os_string(),
+            directories: handle.directories,
+            engine: handle.engine.to_string(),
+        }
+    }
+}
+
+#[pyclass(get_all)]
+pub struct KanshiStatus {
+    pub status: String,
+    pub error: Option<Py<PyBaseException>>,
 }
 
 #[pyclass(get_all)]
 #[derive(Clone)]
 pub struct KanshiEventTarget {
-    pub previous_path: Option<String>,
-    pub new_path: Option<String>,
-    pub path: String,
+    pub previous_path: Option<OsString>,
+    pub new_path: Option<OsString>,
+    pub path: OsString,
+    pub relative_path: Option<OsString>,
     pub kind: String,
 }
 
 #[pymethods]
 impl KanshiPy {
     #[staticmethod]
-    pub fn new(force_engine: &str) -> PyResult<KanshiPy> {
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (force_engine, event_buffer_capacity=DEFAULT_EVENT_BUFFER_CAPACITY, backpressure="drop_oldest", split_renames=false, events=None, recursive=true, max_depth=None, follow_symlinks=false, root_paths="as_requested", include=None, exclude=None, respect_ignore_files=false, debounce=None, debounce_max_wait=None, atomic_saves=None))]
+    pub fn new(
+        force_engine: &str,
+        event_buffer_capacity: usize,
+        backpressure: &str,
+        split_renames: bool,
+        events: Option<Vec<String>>,
+        recursive: bool,
+        max_depth: Option<usize>,
+        follow_symlinks: bool,
+        root_paths: &str,
+        include: Option<Vec<String>>,
+        exclude: Option<Vec<String>>,
+        respect_ignore_files: bool,
+        debounce: Option<f64>,
+        debounce_max_wait: Option<f64>,
+        atomic_saves: Option<f64>,
+    ) -> PyResult<KanshiPy> {
         let engine = if let Ok(engine) = KanshiEngines::from(force_engine) {
             Some(engine)
         } else {
             None
         };
 
+        let backpressure =
+            BackpressurePolicy::from(backpressure).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let root_paths =
+            RootPaths::from(root_paths).map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        let debounce = debounce
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let debounce_max_wait = debounce_max_wait
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let debounce =
+            debounce.map(|debounce| (debounce, debounce_max_wait.unwrap_or(debounce * 10)));
+        let atomic_saves = atomic_saves
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        let event_mask = if let Some(events) = events {
+            let mut event_mask = EventMask::empty();
+            for class in events {
+                event_mask |=
+                    EventMask::from(&class).map_err(|e| PyValueError::new_err(e.to_string()))?;
+            }
+            event_mask
+        } else {
+            EventMask::default()
+        };
+
+        let recursion = match (recursive, max_depth) {
+            (false, _) => RecursionMode::NonRecursive,
+            (true, Some(max_depth)) => RecursionMode::MaxDepth(max_depth),
+            (true, None) => RecursionMode::Recursive,
+        };
+
         let kanshi = Kanshi::new(KanshiOptions {
             force_engine: engine,
+            event_buffer_capacity,
+            backpressure,
+            split_renames,
+            event_mask,
+            recursion,
+            follow_symlinks,
+            root_paths,
+            include: include.unwrap_or_default(),
+            exclude: exclude.unwrap_or_default(),
+            respect_ignore_files,
+            atomic_saves,
         })
-        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+        .map_err(to_py_err)?;
 
-        Ok(KanshiPy { kanshi })
+        Ok(KanshiPy { kanshi, debounce })
     }
 
-    pub fn watch<'py>(&self, dir: &str, py: Python<'py>) -> PyResult<()> {
+    pub fn watch<'py>(&self, dir: PathBuf, py: Python<'py>) -> PyResult<WatchHandle> {
         let runtime = get_runtime(py);
 
         if let Ok(rt) = runtime {
@@ -58,7 +168,22 @@ impl KanshiPy {
                     self.kanshi
                         .watch(dir)
                         .await
-                        .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+                        .map(WatchHandle::from)
+                        .map_err(to_py_err)
+                })
+            })
+        } else {
+            Err(PyRuntimeError::new_err(runtime.err().unwrap().to_string()))
+        }
+    }
+
+    pub fn unwatch<'py>(&self, dir: PathBuf, py: Python<'py>) -> PyResult<()> {
+        let runtime = get_runtime(py);
+
+        if let Ok(rt) = runtime {
+            py.allow_threads(|| {
+                rt.block_on(async move {
+                    self.kanshi.unwatch(dir).await.map_err(to_py_err)
                 })
             })
         } else {
@@ -66,31 +191,92 @@ impl KanshiPy {
         }
     }
 
+    pub fn watches(&self) -> Vec<WatchHandle> {
+        self.kanshi
+            .watches()
+            .into_iter()
+            .map(WatchHandle::from)
+            .collect()
+    }
+
     // py_callable signature: (event) -> None
-    pub fn start<'py>(&self, py_callable: Py<PyAny>, py: Python<'py>) -> PyResult<()> {
-        if !py_callable.bind_borrowed(py).is_callable() {
+    // py_status_callable signature: (status) -> None
+    pub fn start<'py>(
+        &self,
+        py_callable: Py<PyAny>,
+        py_status_callable: Py<PyAny>,
+        py: Python<'py>,
+    ) -> PyResult<()> {
+        if !py_callable.bind_borrowed(py).is_callable()
+            || !py_status_callable.bind_borrowed(py).is_callable()
+        {
             Err(PyAttributeError::new_err(
                 "A callable like a function, method or lambda was not passed to this method.",
             ))
         } else {
             let runtime = get_runtime(py);
             let kanshi = self.kanshi.clone();
-            let mut stream = kanshi.get_events_stream();
+            let mut stream = match self.debounce {
+                Some((debounce, max_wait)) => {
+                    kanshi.get_debounced_events_stream(debounce, max_wait)
+                }
+                None => kanshi.get_events_stream(),
+            };
+            let mut status_stream = kanshi.get_status_stream();
+            let py_warn_callable = py_status_callable.clone_ref(py);
 
             if let Ok(rt) = runtime {
+                rt.spawn(async move {
+                    while let Some(status) = status_stream.next().await {
+                        let (status, error) = match status {
+                            kanshi::KanshiStatus::Started => ("started", None),
+                            kanshi::KanshiStatus::Warning(e) => ("warning", Some(e)),
+                            kanshi::KanshiStatus::Error(e) => ("error", Some(e)),
+                            kanshi::KanshiStatus::Stopped => ("stopped", None),
+                        };
+                        Python::with_gil(|py| {
+                            report_status(py, &py_status_callable, status, error.map(to_py_err))
+                        });
+                    }
+                });
+
                 rt.spawn(async move {
                     while let Some(event) = stream.next().await {
                         let res = Python::with_gil(|py| -> PyResult<()> {
-                            let mut previous_path: Option<String> = None;
-                            let mut new_path: Option<String> = None;
+                            let mut previous_path: Option<OsString> = None;
+                            let mut new_path: Option<OsString> = None;
+                            let mut lost: Option<u64> = None;
+                            let mut root: Option<OsString> = None;
+                            let mut attributes: Option<Vec<String>> = None;
 
                             let event_type_str = match &event.event_type {
                                 FileSystemEventType::MovedFrom(path) => {
-                                    previous_path = Some(path.to_str().unwrap().to_string());
+                                    previous_path = Some(path.clone().into_os_string());
                                     event.event_type.to_string()
                                 }
                                 FileSystemEventType::MovedTo(path) => {
-                                    new_path = Some(path.to_str().unwrap().to_string());
+                                    new_path = Some(path.clone().into_os_string());
+                                    event.event_type.to_string()
+                                }
+                                FileSystemEventType::Rename { from, .. } => {
+                                    previous_path = Some(from.clone().into_os_string());
+                                    event.event_type.to_string()
+                                }
+                                FileSystemEventType::Overflow {
+                                    lost: lost_count,
+                                    root: overflow_root,
+                                } => {
+                                    lost = *lost_count;
+                                    root = overflow_root.clone().map(PathBuf::into_os_string);
+                                    event.event_type.to_string()
+                                }
+                                FileSystemEventType::Metadata(Some(changes)) => {
+                                    attributes = Some(
+                                        changes
+                                            .iter_names()
+                                            .map(|(name, _)| name.to_lowercase())
+                                            .collect(),
+                                    );
                                     event.event_type.to_string()
                                 }
                                 x => x.to_string(),
@@ -98,16 +284,23 @@ impl KanshiPy {
 
                             let py_event = KanshiEvent {
                                 event_type: event_type_str,
+                                sequence: event.sequence,
+                                // Seconds since the Unix epoch, like `time.time()`.
+                                timestamp: event
+                                    .timestamp
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_secs_f64(),
+                                lost,
+                                root,
+                                attributes,
+                                watch_id: event.watch_id,
                                 target: event.target.map(|x| KanshiEventTarget {
                                     previous_path,
                                     new_path,
-                                    path: x.path.into_string().unwrap(),
-                                    kind: match x.kind {
-                                        FileSystemTargetKind::Directory => {
-                                            String::from("directory")
-                                        }
-                                        FileSystemTargetKind::File => String::from("file"),
-                                    },
+                                    path: x.path.into_os_string(),
+                                    relative_path: x.relative_path.map(PathBuf::into_os_string),
+                                    kind: x.kind.to_string(),
                                 }),
                             };
 
@@ -119,17 +312,18 @@ impl KanshiPy {
                             Ok(())
                         });
 
+                        // Passed on as a warning, so that it doesn't go unnoticed.
                         if let Err(e) = res {
-                            println!("{e}");
+                            Python::with_gil(|py| {
+                                report_status(py, &py_warn_callable, "warning", Some(e))
+                            });
                         }
                     }
                 });
 
                 rt.spawn(async move {
-                    let ret = kanshi.start().await;
-                    if let Err(e) = ret {
-                        println!("{e}");
-                    }
+                    // A failure is reported to the status callable as an "error".
+                    let _ = kanshi.start().await;
                 });
 
                 Ok(())
@@ -139,16 +333,74 @@ impl KanshiPy {
         }
     }
 
+    /// Blocks until `start()` is listening. Returns `False` if `timeout` seconds pass first.
+    #[pyo3(signature = (timeout=None))]
+    pub fn ready<'py>(&self, timeout: Option<f64>, py: Python<'py>) -> PyResult<bool> {
+        let timeout = timeout
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let runtime = get_runtime(py);
+
+        if let Ok(rt) = runtime {
+            py.allow_threads(|| {
+                rt.block_on(async move {
+                    let ready = self.kanshi.ready();
+                    let ret = match timeout {
+                        Some(timeout) => match tokio::time::timeout(timeout, ready).await {
+                            Ok(ret) => ret.map(|_| true),
+                            Err(_) => Ok(false),
+                        },
+                        None => ready.await.map(|_| true),
+                    };
+                    ret.map_err(to_py_err)
+                })
+            })
+        } else {
+            Err(PyRuntimeError::new_err(runtime.err().unwrap().to_string()))
+        }
+    }
+
+    /// Awaitable version of `ready()`, for use with `asyncio`.
+    pub fn ready_async<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let kanshi = self.kanshi.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            kanshi.ready().await.map_err(to_py_err)
+        })
+    }
+
     pub fn close(&self) -> PyResult<bool> {
         Ok(self.kanshi.close())
     }
 }
 
 /// A Python module implemented in Rust.
+/// Calls `py_status_callable` with a status. Anything it raises has nowhere to go, so
+/// it is passed to `sys.unraisablehook`.
+fn report_status(
+    py: Python,
+    py_status_callable: &Py<PyAny>,
+    status: &str,
+    error: Option<PyErr>,
+) {
+    let py_status = KanshiStatus {
+        status: status.to_owned(),
+        error: error.map(|e| e.into_value(py)),
+    };
+    let res = Py::new(py, py_status)
+        .and_then(|py_status: Py<KanshiStatus>| py_status_callable.call1(py, (py_status,)));
+    if let Err(e) = res {
+        e.write_unraisable(py, None);
+    }
+}
+
 #[pymodule]
 fn _kanshipy(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<KanshiPy>()?;
     m.add_class::<KanshiEvent>()?;
     m.add_class::<KanshiEventTarget>()?;
+    m.add_class::<KanshiStatus>()?;
+    m.add_class::<WatchHandle>()?;
+    m.add("WatchLimitError", m.py().get_type::<WatchLimitError>())?;
     Ok(())
 }
@@ -10,6 +10,7 @@ kanshi.watch("./test_dir")
 kanshi.subscribe(onEvent)
 
 kanshi.start()
+kanshi.ready()
 
 time.sleep(10)
 
@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::{
+    listings::Listings, FileSystemEvent, FileSystemEventType, FileSystemTarget,
+    FileSystemTargetKind,
+};
+
+/// Reports a file saved through a temporary file as a single `Modify` of the file.
+///
+/// Two patterns are recognised, as long as they complete within `window`:
+/// - A file created, written and renamed over the target, such as `file.tmp1234` or
+///   JetBrains' `file___jb_tmp___`. A file created and deleted again, such as the
+///   `4913` vim writes to test a directory, is dropped. If the target wasn't in its
+///   directory when the temporary file was first held, it is reported as created
+///   instead.
+/// - The target renamed to a backup such as `file~` or `file___jb_old___`, rewritten,
+///   and the backup deleted. A backup that is kept is reported as created.
+///
+/// To tell, newly created files and renamed files are held back for up to `window`.
+/// Other events are not delayed.
+pub(crate) fn atomic_saves(
+    mut events: Pin<Box<dyn Stream<Item = FileSystemEvent> + Send>>,
+    window: Duration,
+) -> Pin<Box<dyn Stream<Item = FileSystemEvent> + Send>> {
+    Box::pin(stream! {
+        let mut saves = Saves::default();
+        loop {
+            let next = match saves.next_deadline() {
+                Some(deadline) => tokio::time::timeout_at(deadline, events.next()).await.ok(),
+                None => Some(events.next().await),
+            };
+
+            match next {
+                None => {
+                    for event in saves.due(Instant::now()) {
+                        yield event;
+                    }
+                }
+                Some(None) => {
+                    for event in saves.due(Instant::now() + window) {
+                        yield event;
+                    }
+                    break;
+                }
+                Some(Some(event)) => {
+                    for event in saves.push(event, Instant::now() + window) {
+                        yield event;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[derive(Default)]
+struct Saves {
+    // Files created within the window, which may be the temporary file of a save.
+    temps: HashMap<PathBuf, Held>,
+    // Files renamed within the window, which may have been moved aside as a backup.
+    replaced: HashMap<PathBuf, Replaced>,
+    // Where the `MovedFrom` half of a split rename goes, once its `MovedTo` was handled.
+    split: Option<Split>,
+    // The directories held temporary files are in, as they were when first held.
+    listings: Listings,
+    next_order: u64,
+}
+
+struct Held {
+    events: Vec<FileSystemEvent>,
+    deadline: Instant,
+    // Orders groups released together by when they were first held.
+    order: u64,
+}
+
+struct Replaced {
+    backup: PathBuf,
+    held: Held,
+    // Set once a new file took the place of the one moved aside.
+    rewritten: Option<FileSystemTarget>,
+}
+
+struct Split {
+    from: PathBuf,
+    to: PathBuf,
+    into: SplitHalf,
+}
+
+enum SplitHalf {
+    Dropped,
+    Passed,
+    HeldWith(PathBuf),
+}
+
+impl Saves {
+    fn next_deadline(&self) -> Option<Instant> {
+        let temps = self.temps.values().map(|held| held.deadline);
+        let replaced = self
+            .replaced
+            .values()
+            .map(|replaced| replaced.held.deadline);
+        temps.chain(replaced).min()
+    }
+
+    /// Releases the groups held since before `now`, in the order they were held.
+    fn due(&mut self, now: Instant) -> Vec<FileSystemEvent> {
+        let temps: Vec<PathBuf> = self
+            .temps
+            .iter()
+            .filter(|(_, held)| held.deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let replaced: Vec<PathBuf> = self
+            .replaced
+            .iter()
+            .filter(|(_, replaced)| replaced.held.deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut groups: Vec<(u64, Vec<FileSystemEvent>)> = Vec::new();
+        for path in temps {
+            if let Some(held) = self.temps.remove(&path) {
+                groups.push((held.order, held.events));
+            }
+        }
+        for path in replaced {
+            if let Some(replaced) = self.replaced.remove(&path) {
+                groups.push((replaced.held.order, replaced.into_events()));
+            }
+        }
+        groups.sort_by_key(|(order, _)| *order);
+        groups.into_iter().flat_map(|(_, events)| events).collect()
+    }
+
+    /// Adds `event`, returning the events to yield right away.
+    fn push(&mut self, event: FileSystemEvent, deadline: Instant) -> Vec<FileSystemEvent> {
+        if let FileSystemEventType::MovedFrom(from) = &event.event_type {
+            return self.push_moved_from(from.clone(), event);
+        }
+        let Some(target) = event.target.clone() else {
+            return vec![event];
+        };
+        let path = target.path.clone();
+
+        match &event.event_type {
+            FileSystemEventType::Rename { from, to } => {
+                let (from, to) = (from.clone(), to.clone());
+                self.rename(from, to, event, deadline).0
+            }
+            // The first half of a split rename, whose target is where the item came from.
+            FileSystemEventType::MovedTo(to) => {
+                let to = to.clone();
+                let (ready, into) = self.rename(path.clone(), to.clone(), event, deadline);
+                self.split = Some(Split {
+                    from: path,
+                    to,
+                    into,
+                });
+                ready
+            }
+            FileSystemEventType::Create => {
+                if let Some(replaced) = self.replaced.get_mut(&path) {
+                    replaced.rewritten = Some(target);
+                    replaced.held.events.push(event);
+                } else if let Some(held) = self.temps.get_mut(&path) {
+                    held.events.push(event);
+                } else if target.kind == FileSystemTargetKind::Directory {
+                    return vec![event];
+                } else {
+                    self.listings.keep(self.temps.keys());
+                    self.listings.list_parent(&path);
+                    let held = self.hold(event, deadline);
+                    self.temps.insert(path, held);
+                }
+                Vec::new()
+            }
+            FileSystemEventType::Delete => {
+                // It came and went, like vim's `4913`.
+                if self.temps.remove(&path).is_some() {
+                    return Vec::new();
+                }
+                if let Some(original) = self.backed_up(&path) {
+                    let replaced = self.replaced.remove(&original).unwrap();
+                    return match replaced.rewritten {
+                        Some(target) => vec![retype(event, FileSystemEventType::Modify, target)],
+                        None => {
+                            let mut events = replaced.held.events;
+                            events.push(event);
+                            events
+                        }
+                    };
+                }
+                let mut ready = self.release(&path);
+                ready.push(event);
+                ready
+            }
+            _ => {
+                if let Some(held) = self.temps.get_mut(&path) {
+                    held.events.push(event);
+                } else if let Some(replaced) = self.replaced.get_mut(&path) {
+                    replaced.held.events.push(event);
+                } else {
+                    return vec![event];
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        from: PathBuf,
+        to: PathBuf,
+        event: FileSystemEvent,
+        deadline: Instant,
+    ) -> (Vec<FileSystemEvent>, SplitHalf) {
+        let Some(target) = event.target.as_ref() else {
+            return (vec![event], SplitHalf::Passed);
+        };
+
+        // A temporary file took the place of `to`.
+        if self.temps.remove(&from).is_some() {
+            let target = retarget(target, &to);
+            if let Some(replaced) = self.replaced.get_mut(&to) {
+                replaced.rewritten = Some(target);
+                return (Vec::new(), SplitHalf::Dropped);
+            }
+            // Only a file that was there before, or held as created since, was saved.
+            // Otherwise it is a new one.
+            let mut ready = self.release(&to);
+            let event_type = if self.listings.listed(&to) || !ready.is_empty() {
+                FileSystemEventType::Modify
+            } else {
+                FileSystemEventType::Create
+            };
+            ready.push(retype(event, event_type, target));
+            return (ready, SplitHalf::Dropped);
+        }
+
+        if target.kind == FileSystemTargetKind::Directory {
+            return (vec![event], SplitHalf::Passed);
+        }
+        // `from` may have been moved aside as a backup.
+        let mut ready = self.release(&from);
+        ready.extend(self.release(&to));
+        let held = self.hold(event, deadline);
+        self.replaced.insert(
+            from.clone(),
+            Replaced {
+                backup: to,
+                held,
+                rewritten: None,
+            },
+        );
+        (ready, SplitHalf::HeldWith(from))
+    }
+
+    fn push_moved_from(&mut self, from: PathBuf, event: FileSystemEvent) -> Vec<FileSystemEvent> {
+        let to = event.target.as_ref().map(|target| target.path.clone());
+        let Some(split) = self
+            .split
+            .take()
+            .filter(|split| split.from == from && Some(&split.to) == to.as_ref())
+        else {
+            return vec![event];
+        };
+
+        match split.into {
+            SplitHalf::Dropped => Vec::new(),
+            SplitHalf::Passed => vec![event],
+            SplitHalf::HeldWith(path) => match self.replaced.get_mut(&path) {
+                Some(replaced) => {
+                    replaced.held.events.push(event);
+                    Vec::new()
+                }
+                None => vec![event],
+            },
+        }
+    }
+
+    fn hold(&mut self, event: FileSystemEvent, deadline: Instant) -> Held {
+        self.next_order += 1;
+        Held {
+            events: vec![event],
+            deadline,
+            order: self.next_order,
+        }
+    }
+
+    /// Releases what is held for `path`, so it is yielded before a newer event.
+    fn release(&mut self, path: &Path) -> Vec<FileSystemEvent> {
+        let mut events = self
+            .temps
+            .remove(path)
+            .map(|held| held.events)
+            .unwrap_or_default();
+        if let Some(replaced) = self.replaced.remove(path) {
+            events.extend(replaced.into_events());
+        }
+        events
+    }
+
+    /// The file that `backup` was moved aside from.
+    fn backed_up(&self, backup: &Path) -> Option<PathBuf> {
+        self.replaced
+            .iter()
+            .find(|(_, replaced)| replaced.backup == backup)
+            .map(|(path, _)| path.clone())
+    }
+}
+
+impl Replaced {
+    /// What is reported if the backup is still around once the window passed.
+    fn into_events(mut self) -> Vec<FileSystemEvent> {
+        let Some(target) = self.rewritten else {
+            return self.held.events;
+        };
+        // The rename to the backup comes first.
+        let mut backup = self.held.events[0].clone();
+        let last = self.held.events.pop().unwrap();
+        if let Some(backup_target) = &mut backup.target {
+            *backup_target = retarget(backup_target, &self.backup);
+        }
+        backup.event_type = FileSystemEventType::Create;
+        vec![retype(last, FileSystemEventType::Modify, target), backup]
+    }
+}
+
+fn retype(
+    mut event: FileSystemEvent,
+    event_type: FileSystemEventType,
+    target: FileSystemTarget,
+) -> FileSystemEvent {
+    event.event_type = event_type;
+    event.target = Some(target);
+    event
+}
+
+/// `target` moved to `path`, in the same watch.
+fn retarget(target: &FileSystemTarget, path: &Path) -> FileSystemTarget {
+    let relative_path = target.relative_path.as_ref().and_then(|relative| {
+        let root = target.path.ancestors().nth(relative.components().count())?;
+        path.strip_prefix(root).ok().map(Path::to_path_buf)
+    });
+    FileSystemTarget {
+        kind: target.kind.clone(),
+        path: path.to_path_buf(),
+        relative_path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration, time::SystemTime};
+
+    use futures::StreamExt;
+
+    use super::atomic_saves;
+    use crate::{FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind};
+
+    fn event(event_type: FileSystemEventType, path: impl Into<PathBuf>) -> FileSystemEvent {
+        FileSystemEvent::new(
+            event_type,
+            Some(FileSystemTarget::new(path, FileSystemTargetKind::File)),
+            SystemTime::now(),
+        )
+    }
+
+    fn rename(
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+        split_renames: bool,
+    ) -> Vec<FileSystemEvent> {
+        FileSystemEvent::rename(
+            from,
+            to,
+            FileSystemTargetKind::File,
+            SystemTime::now(),
+            split_renames,
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_atomic_saves_as_a_modify() {
+        use FileSystemEventType::*;
+
+        // a was there before, without any events of its own.
+        let temp = tempfile::tempdir().unwrap();
+        let w = |name: &str| temp.path().join(name);
+        std::fs::write(w("a"), "").unwrap();
+
+        let mut events = Vec::new();
+        // A library writing a temporary file, with split renames.
+        events.extend([
+            event(Create, w("a.tmp1234")),
+            event(Modify, w("a.tmp1234")),
+            event(CloseWrite, w("a.tmp1234")),
+        ]);
+        events.extend(rename(w("a.tmp1234"), w("a"), true));
+        // Vim, testing the directory and writing a backup.
+        events.extend([event(Create, w("4913")), event(Delete, w("4913"))]);
+        events.extend(rename(w("b"), w("b~"), false));
+        events.extend([
+            event(Create, w("b")),
+            event(Modify, w("b")),
+            event(CloseWrite, w("b")),
+            event(Delete, w("b~")),
+        ]);
+        // JetBrains' safe write.
+        events.extend([
+            event(Create, w("c___jb_tmp___")),
+            event(Modify, w("c___jb_tmp___")),
+        ]);
+        events.extend(rename(w("c"), w("c___jb_old___"), false));
+        events.extend(rename(w("c___jb_tmp___"), w("c"), false));
+        events.push(event(Delete, w("c___jb_old___")));
+        // Anything else is left alone.
+        events.extend([event(Modify, w("d")), event(Create, w("e"))]);
+        events.extend(rename(w("f"), w("g"), false));
+        // A new file written the same way as the first one is created.
+        events.extend([
+            event(Create, w("h.tmp5678")),
+            event(CloseWrite, w("h.tmp5678")),
+        ]);
+        events.extend(rename(w("h.tmp5678"), w("h"), true));
+
+        let saved: Vec<(FileSystemEventType, PathBuf)> = atomic_saves(
+            Box::pin(futures::stream::iter(events)),
+            Duration::from_secs(60),
+        )
+        .map(|event| {
+            let path = event.target.unwrap().path;
+            (event.event_type, path)
+        })
+        .collect()
+        .await;
+
+        assert_eq!(
+            saved,
+            [
+                (Modify, w("a")),
+                (Modify, w("b")),
+                (Modify, w("c")),
+                (Modify, w("d")),
+                (Create, w("h")),
+                // Held back until the window passes, or here the stream ends.
+                (Create, w("e")),
+                (
+                    Rename {
+                        from: w("f"),
+                        to: w("g")
+                    },
+                    w("g")
+                ),
+            ]
+        );
+    }
+}
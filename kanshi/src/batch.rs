@@ -0,0 +1,328 @@
+use std::{collections::HashMap, path::PathBuf, pin::Pin, time::Duration};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::{listings::Listings, ChangeBatch, FileSystemEvent, FileSystemEventType, MoveDirection};
+
+/// Collects events until none came for `quiet`, or `max_wait` after the first one,
+/// then yields what they changed as one `ChangeBatch`. Batches that net out to no
+/// change at all are not yielded.
+pub(crate) fn batch(
+    mut events: Pin<Box<dyn Stream<Item = FileSystemEvent> + Send>>,
+    quiet: Duration,
+    max_wait: Duration,
+) -> Pin<Box<dyn Stream<Item = ChangeBatch> + Send>> {
+    Box::pin(stream! {
+        let mut changes = Changes::default();
+        let mut first_at: Option<Instant> = None;
+        let mut deadline = Instant::now();
+        loop {
+            let next = match first_at {
+                Some(_) => tokio::time::timeout_at(deadline, events.next()).await.ok(),
+                None => Some(events.next().await),
+            };
+
+            match next {
+                // Everything has been quiet for long enough, or the batch is due anyway.
+                None => {
+                    first_at = None;
+                    if let Some(batch) = std::mem::take(&mut changes).into_batch() {
+                        yield batch;
+                    }
+                }
+                Some(None) => {
+                    if let Some(batch) = changes.into_batch() {
+                        yield batch;
+                    }
+                    break;
+                }
+                Some(Some(event)) => {
+                    changes.push(event);
+                    let now = Instant::now();
+                    let first_at = *first_at.get_or_insert(now);
+                    deadline = (now + quiet).min(first_at + max_wait);
+                }
+            }
+        }
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Added,
+    Modified,
+    Removed,
+}
+
+#[derive(Default)]
+struct Changes {
+    by_path: HashMap<PathBuf, Change>,
+    // The path each renamed item had before the batch, by the path it has now.
+    renames: HashMap<PathBuf, PathBuf>,
+    // The directories events in the batch were in, as they were first seen.
+    listings: Listings,
+    overflow: bool,
+}
+
+impl Changes {
+    fn push(&mut self, event: FileSystemEvent) {
+        // Whether a rename replaced something that was at its destination before. The
+        // directory is read by the rename itself too late to tell.
+        let replaced = match &event.event_type {
+            FileSystemEventType::Rename { to, .. } | FileSystemEventType::MovedTo(to) => {
+                self.listings.listed(to)
+            }
+            FileSystemEventType::MovedFrom(_) | FileSystemEventType::Overflow { .. } => false,
+            _ => {
+                if let Some(target) = &event.target {
+                    self.listings.list_parent(&target.path);
+                }
+                false
+            }
+        };
+
+        let direction = event.move_direction;
+        let path = event.target.map(|target| target.path);
+        match (event.event_type, path) {
+            (FileSystemEventType::Create, Some(path)) => self.add(path),
+            (FileSystemEventType::Delete, Some(path)) => self.remove(path),
+            (
+                FileSystemEventType::Modify
+                | FileSystemEventType::CloseWrite
+                | FileSystemEventType::Metadata(_),
+                Some(path),
+            ) => self.modify(path),
+            (FileSystemEventType::Rename { from, to }, _) => self.rename(from, to, replaced),
+            // The other half of a split rename, whose target is where the item came from.
+            (FileSystemEventType::MovedTo(to), Some(from)) => self.rename(from, to, replaced),
+            (FileSystemEventType::MovedFrom(_), _) => {}
+            (FileSystemEventType::Move, Some(path)) => match direction {
+                Some(MoveDirection::In) => self.add(path),
+                Some(MoveDirection::Out) => self.remove(path),
+                // A watched item moved itself, so its path has to be looked at again.
+                None => self.modify(path),
+            },
+            (FileSystemEventType::Overflow { .. }, _) => self.overflow = true,
+            // Reads don't change anything.
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, path: PathBuf) {
+        let change = match self.by_path.get(&path) {
+            // It was replaced.
+            Some(Change::Removed | Change::Modified) => Change::Modified,
+            _ => Change::Added,
+        };
+        self.by_path.insert(path, change);
+    }
+
+    fn modify(&mut self, path: PathBuf) {
+        self.by_path.entry(path).or_insert(Change::Modified);
+    }
+
+    fn remove(&mut self, path: PathBuf) {
+        // A renamed item that is removed is gone from where it was before the batch.
+        let path = match self.renames.remove(&path) {
+            Some(origin) => {
+                self.by_path.remove(&path);
+                origin
+            }
+            None => path,
+        };
+        match self.by_path.get(&path) {
+            // It came and went within the batch.
+            Some(Change::Added) => {
+                self.by_path.remove(&path);
+            }
+            _ => {
+                self.by_path.insert(path, Change::Removed);
+            }
+        }
+    }
+
+    fn rename(&mut self, from: PathBuf, to: PathBuf, replaced: bool) {
+        // Something removed earlier in the batch was there before it, too.
+        let replaced = replaced || self.by_path.get(&to) == Some(&Change::Removed);
+        if replaced {
+            // An item renamed to `to` earlier in the batch is gone from where it was.
+            if let Some(origin) = self.renames.remove(&to) {
+                self.remove(origin);
+            }
+        }
+
+        let change = self.by_path.remove(&from);
+        if change == Some(Change::Added) {
+            // Written under another name and renamed into place.
+            return if replaced {
+                self.replace(to)
+            } else {
+                self.add(to)
+            };
+        }
+
+        let origin = self.renames.remove(&from).unwrap_or(from);
+        if origin != to {
+            self.renames.insert(to.clone(), origin);
+        }
+        if change == Some(Change::Modified) || replaced {
+            self.replace(to);
+        }
+    }
+
+    fn replace(&mut self, path: PathBuf) {
+        // Something created within the batch is still new.
+        if self.by_path.get(&path) != Some(&Change::Added) {
+            self.by_path.insert(path, Change::Modified);
+        }
+    }
+
+    fn into_batch(self) -> Option<ChangeBatch> {
+        let mut batch = ChangeBatch {
+            overflow: self.overflow,
+            ..Default::default()
+        };
+        for (path, change) in self.by_path {
+            match change {
+                Change::Added => batch.added.push(path),
+                Change::Modified => batch.modified.push(path),
+                Change::Removed => batch.removed.push(path),
+            }
+        }
+        batch.renamed = self
+            .renames
+            .into_iter()
+            .map(|(to, from)| (from, to))
+            .collect();
+
+        batch.added.sort();
+        batch.modified.sort();
+        batch.removed.sort();
+        batch.renamed.sort();
+        (!batch.is_empty()).then_some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration, time::SystemTime};
+
+    use futures::StreamExt;
+
+    use super::batch;
+    use crate::{
+        FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, MoveDirection,
+    };
+
+    fn event(event_type: FileSystemEventType, path: impl Into<PathBuf>) -> FileSystemEvent {
+        FileSystemEvent::new(
+            event_type,
+            Some(FileSystemTarget::new(path, FileSystemTargetKind::File)),
+            SystemTime::now(),
+        )
+    }
+
+    fn rename(from: impl Into<PathBuf>, to: impl Into<PathBuf>) -> FileSystemEvent {
+        FileSystemEvent::rename(
+            from,
+            to,
+            FileSystemTargetKind::File,
+            SystemTime::now(),
+            false,
+        )
+        .remove(0)
+    }
+
+    fn moved(direction: MoveDirection, path: impl Into<PathBuf>) -> FileSystemEvent {
+        FileSystemEvent::moved(
+            direction,
+            FileSystemTarget::new(path, FileSystemTargetKind::File),
+            SystemTime::now(),
+        )
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[tokio::test]
+    async fn collects_the_net_changes_of_a_batch() {
+        use FileSystemEventType::*;
+
+        let events = futures::stream::iter([
+            event(Create, "/w/a"),
+            event(Modify, "/w/a"),
+            event(Modify, "/w/a"),
+            event(Create, "/w/tmp"),
+            event(Delete, "/w/tmp"),
+            event(CloseWrite, "/w/b"),
+            event(Open, "/w/c"),
+            event(Delete, "/w/d"),
+            event(Create, "/w/d"),
+            rename("/w/e", "/w/f"),
+            rename("/w/f", "/w/g"),
+            event(Modify, "/w/g"),
+            rename("/w/h", "/w/i"),
+            event(Delete, "/w/i"),
+        ]);
+        let batches: Vec<_> = batch(
+            Box::pin(events),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .collect()
+        .await;
+
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.added, paths(&["/w/a"]));
+        assert_eq!(batch.modified, paths(&["/w/b", "/w/d", "/w/g"]));
+        assert_eq!(batch.removed, paths(&["/w/h"]));
+        assert_eq!(
+            batch.renamed,
+            [(PathBuf::from("/w/e"), PathBuf::from("/w/g"))]
+        );
+        assert!(!batch.overflow);
+    }
+
+    #[tokio::test]
+    async fn renames_over_existing_files_are_modifications() {
+        use FileSystemEventType::*;
+
+        // a and c were there before, without any events of their own.
+        let temp = tempfile::tempdir().unwrap();
+        let w = |name: &str| temp.path().join(name);
+        std::fs::write(w("a"), "").unwrap();
+        std::fs::write(w("c"), "").unwrap();
+
+        let events = futures::stream::iter([
+            // An editor saving over a file through a temporary one.
+            event(Create, w("a.tmp")),
+            event(Modify, w("a.tmp")),
+            rename(w("a.tmp"), w("a")),
+            // A new file written the same way.
+            event(Create, w("b.tmp")),
+            rename(w("b.tmp"), w("b")),
+            // A file moved over another one.
+            rename(w("d"), w("c")),
+            moved(MoveDirection::In, w("e")),
+            moved(MoveDirection::Out, w("f")),
+        ]);
+        let batches: Vec<_> = batch(
+            Box::pin(events),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .collect()
+        .await;
+
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.added, [w("b"), w("e")]);
+        assert_eq!(batch.modified, [w("a"), w("c")]);
+        assert_eq!(batch.removed, [w("f")]);
+        assert_eq!(batch.renamed, [(w("d"), w("c"))]);
+    }
+}
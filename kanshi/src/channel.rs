@@ -0,0 +1,559 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use async_stream::stream;
+use futures::Stream;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, watch, Notify,
+};
+
+use crate::{
+    filter::PathFilter, watches::WatchRegistry, BackpressurePolicy, EventMask, FileSystemEvent,
+    FileSystemEventType, FileSystemTargetKind, KanshiError, KanshiStatus, RootPaths,
+};
+
+/// Status changes are rare, so a small buffer is plenty.
+const STATUS_BUFFER_CAPACITY: usize = 16;
+
+/// Fans events out from an engine's reader loop to every subscribed stream,
+/// applying the configured `BackpressurePolicy` and stamping each event with
+/// the instance's next sequence number. Events outside the configured
+/// `EventMask`, or kept out by the include and exclude globs, are dropped here so
+/// every engine filters the same way, and events are tagged with the watch they
+/// belong to.
+/// It also carries the engine's `KanshiStatus` notifications.
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    delivery: Delivery,
+    next_sequence: Arc<AtomicU64>,
+    event_mask: EventMask,
+    filter: PathFilter,
+    status: broadcast::Sender<KanshiStatus>,
+    readiness: Arc<watch::Sender<Readiness>>,
+    watches: WatchRegistry,
+}
+
+/// How far the engine's read loop got, for `ready()`.
+#[derive(Clone)]
+enum Readiness {
+    Starting,
+    Listening,
+    Stopped(Option<KanshiError>),
+}
+
+#[derive(Clone)]
+enum Delivery {
+    Broadcast {
+        sender: broadcast::Sender<FileSystemEvent>,
+        capacity: usize,
+        // Only set for `BackpressurePolicy::Block`. Receivers notify it whenever
+        // they free up a slot in the channel.
+        drained: Option<Arc<Notify>>,
+    },
+    Unbounded {
+        subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<FileSystemEvent>>>>,
+    },
+}
+
+pub(crate) struct EventReceiver {
+    receiver: Receiver,
+    // Sequence number this subscriber expects to see next. Used to number the
+    // `Overflow` event reported when it lags behind.
+    next_sequence: u64,
+}
+
+enum Receiver {
+    Broadcast {
+        receiver: broadcast::Receiver<FileSystemEvent>,
+        drained: Option<Arc<Notify>>,
+    },
+    Unbounded(mpsc::UnboundedReceiver<FileSystemEvent>),
+}
+
+impl EventSender {
+    pub(crate) fn new(
+        capacity: usize,
+        policy: BackpressurePolicy,
+        event_mask: EventMask,
+        root_paths: RootPaths,
+        filter: PathFilter,
+    ) -> Result<EventSender, KanshiError> {
+        if capacity == 0 {
+            return Err(KanshiError::InvalidParameter(
+                "event_buffer_capacity must be greater than 0".to_owned(),
+            ));
+        }
+
+        let delivery = match policy {
+            BackpressurePolicy::DropOldest => Delivery::Broadcast {
+                sender: broadcast::channel(capacity).0,
+                capacity,
+                drained: None,
+            },
+            BackpressurePolicy::Block => Delivery::Broadcast {
+                sender: broadcast::channel(capacity).0,
+                capacity,
+                drained: Some(Arc::new(Notify::new())),
+            },
+            BackpressurePolicy::Unbounded => Delivery::Unbounded {
+                subscribers: Arc::new(Mutex::new(Vec::new())),
+            },
+        };
+
+        Ok(EventSender {
+            delivery,
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            event_mask,
+            filter,
+            status: broadcast::channel(STATUS_BUFFER_CAPACITY).0,
+            readiness: Arc::new(watch::channel(Readiness::Starting).0),
+            watches: WatchRegistry::new(root_paths),
+        })
+    }
+
+    pub(crate) fn watches(&self) -> &WatchRegistry {
+        &self.watches
+    }
+
+    pub(crate) fn filter(&self) -> &PathFilter {
+        &self.filter
+    }
+
+    pub(crate) fn subscribe(&self) -> EventReceiver {
+        let receiver = match &self.delivery {
+            Delivery::Broadcast {
+                sender, drained, ..
+            } => Receiver::Broadcast {
+                receiver: sender.subscribe(),
+                drained: drained.clone(),
+            },
+            Delivery::Unbounded { subscribers } => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                subscribers.lock().unwrap().push(tx);
+                Receiver::Unbounded(rx)
+            }
+        };
+
+        EventReceiver {
+            receiver,
+            next_sequence: self.next_sequence.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Whether the include and exclude globs and the ignore files let `event` through.
+    /// A rename passes if either of its paths does.
+    fn passes_filter(&self, event: &FileSystemEvent) -> bool {
+        let is_dir = event
+            .target
+            .as_ref()
+            .is_some_and(|target| target.kind == FileSystemTargetKind::Directory);
+        let wants = |path: &Path| {
+            self.watches.locate(path).is_none_or(|located| {
+                self.filter.wants(&located.relative_path)
+                    && !self.filter.ignores(&located.canonical_path, is_dir)
+            })
+        };
+
+        match &event.event_type {
+            FileSystemEventType::Rename { from, to } => wants(from) || wants(to),
+            _ => event
+                .target
+                .as_ref()
+                .is_none_or(|target| wants(&target.path)),
+        }
+    }
+
+    /// Tags `event` with its watch, and rewrites its paths to start with the root of
+    /// that watch as reported, whichever form of the root the engine read them in.
+    fn locate(&self, event: &mut FileSystemEvent) {
+        let reported = |path: &mut PathBuf| {
+            let located = self.watches.locate(path)?;
+            *path = located.path;
+            Some(located.watch_id)
+        };
+
+        match &mut event.event_type {
+            FileSystemEventType::MovedTo(path) | FileSystemEventType::MovedFrom(path) => {
+                reported(path);
+            }
+            FileSystemEventType::Rename { from, to } => {
+                reported(from);
+                reported(to);
+            }
+            FileSystemEventType::Overflow {
+                root: Some(root), ..
+            } => event.watch_id = reported(root),
+            _ => {}
+        }
+
+        if let Some(target) = &mut event.target {
+            if let Some(located) = self.watches.locate(&target.path) {
+                event.watch_id = Some(located.watch_id);
+                target.path = located.path;
+                target.relative_path = Some(located.relative_path);
+            }
+        }
+    }
+
+    /// Sends an event to every subscriber.
+    /// Returns an error if there are no subscribers left, mirroring `broadcast::Sender::send`.
+    pub(crate) async fn send(&self, mut event: FileSystemEvent) -> Result<(), KanshiError> {
+        if !self.event_mask.wants(&event.event_type) || !self.passes_filter(&event) {
+            return Ok(());
+        }
+
+        self.locate(&mut event);
+
+        match &self.delivery {
+            Delivery::Broadcast {
+                sender,
+                capacity,
+                drained,
+            } => {
+                if let Some(drained) = drained {
+                    // Hold the reader loop until the slowest subscriber has room.
+                    while sender.receiver_count() > 0 && sender.len() >= *capacity {
+                        drained.notified().await;
+                    }
+                }
+
+                event.sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+                sender
+                    .send(event)
+                    .map(|_| ())
+                    .map_err(|_| KanshiError::StreamClosedError)
+            }
+            Delivery::Unbounded { subscribers } => {
+                let mut subscribers = subscribers.lock().unwrap();
+                event.sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+                subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+                if subscribers.is_empty() {
+                    Err(KanshiError::StreamClosedError)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Notifies every status subscriber. Nobody listening is not an error.
+    pub(crate) fn report(&self, status: KanshiStatus) {
+        let _ = self.status.send(status);
+    }
+
+    /// Reports a problem the engine recovered from.
+    pub(crate) fn warn(&self, error: KanshiError) {
+        self.report(KanshiStatus::Warning(error));
+    }
+
+    /// Runs an engine's read loop, reporting `Started`, then `Error` if it fails,
+    /// then `Stopped`.
+    pub(crate) async fn run(
+        &self,
+        read_loop: impl futures::Future<Output = Result<(), KanshiError>>,
+    ) -> Result<(), KanshiError> {
+        self.report(KanshiStatus::Started);
+        let result = read_loop.await;
+        self.stopped(result.as_ref().err().cloned());
+        if let Err(e) = &result {
+            self.report(KanshiStatus::Error(e.clone()));
+        }
+        self.report(KanshiStatus::Stopped);
+        result
+    }
+
+    /// Called by the read loop once the kernel queue is armed and being read, so
+    /// no event happening from now on can be missed.
+    pub(crate) fn listening(&self) {
+        self.readiness.send_if_modified(|readiness| {
+            let starting = matches!(readiness, Readiness::Starting);
+            if starting {
+                *readiness = Readiness::Listening;
+            }
+            starting
+        });
+    }
+
+    /// Marks the engine as stopped, with the error that stopped it, if any.
+    pub(crate) fn stopped(&self, error: Option<KanshiError>) {
+        self.readiness.send_replace(Readiness::Stopped(error));
+    }
+
+    /// Waits until the engine is listening. Fails if it stopped instead.
+    pub(crate) async fn ready(&self) -> Result<(), KanshiError> {
+        let mut receiver = self.readiness.subscribe();
+        let readiness = receiver
+            .wait_for(|readiness| !matches!(readiness, Readiness::Starting))
+            .await
+            .map_err(|_| KanshiError::StreamClosedError)?;
+
+        match &*readiness {
+            Readiness::Stopped(Some(e)) => Err(e.clone()),
+            Readiness::Stopped(None) => Err(KanshiError::StreamClosedError),
+            _ => Ok(()),
+        }
+    }
+
+    /// A stream of status changes that ends after `Stopped`.
+    pub(crate) fn status_stream(&self) -> Pin<Box<dyn Stream<Item = KanshiStatus> + Send>> {
+        let mut receiver = self.status.subscribe();
+
+        Box::pin(stream! {
+            loop {
+                match receiver.recv().await {
+                    Ok(KanshiStatus::Stopped) => {
+                        yield KanshiStatus::Stopped;
+                        break;
+                    }
+                    Ok(status) => yield status,
+                    // Missing a warning is better than holding up the engine.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Same as `send`, for callers that are not running inside an async context.
+    #[cfg(target_os = "macos")]
+    pub(crate) fn send_blocking(&self, event: FileSystemEvent) -> Result<(), KanshiError> {
+        futures::executor::block_on(self.send(event))
+    }
+}
+
+impl EventReceiver {
+    pub(crate) async fn recv(&mut self) -> Result<FileSystemEvent, RecvError> {
+        let val = match &mut self.receiver {
+            Receiver::Broadcast { receiver, drained } => {
+                let val = receiver.recv().await;
+                if let Some(drained) = drained {
+                    drained.notify_one();
+                }
+                val
+            }
+            Receiver::Unbounded(receiver) => receiver.recv().await.ok_or(RecvError::Closed),
+        };
+
+        if let Ok(event) = &val {
+            self.next_sequence = event.sequence + 1;
+        }
+
+        val
+    }
+
+    /// Builds the `Overflow` event reported after this subscriber missed `lost` events.
+    /// It carries the sequence number of the first missed event.
+    pub(crate) fn lagged(&mut self, lost: u64) -> FileSystemEvent {
+        let mut event = FileSystemEvent::overflow(Some(lost), None, SystemTime::now());
+        event.sequence = self.next_sequence;
+        self.next_sequence += lost;
+        event
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        // A dropped subscriber no longer holds back the channel.
+        if let Receiver::Broadcast {
+            drained: Some(drained),
+            ..
+        } = &self.receiver
+        {
+            drained.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::sync::broadcast::error::RecvError;
+
+    use futures::StreamExt;
+
+    use super::{EventSender, PathFilter};
+    use crate::{
+        BackpressurePolicy, EventMask, FileSystemEvent, FileSystemEventType, FileSystemTarget,
+        FileSystemTargetKind, KanshiError, KanshiStatus, RootPaths,
+    };
+
+    #[tokio::test]
+    async fn block_waits_for_slow_subscriber() {
+        let sender = EventSender::new(
+            1,
+            BackpressurePolicy::Block,
+            EventMask::default(),
+            RootPaths::default(),
+            PathFilter::default(),
+        )
+        .unwrap();
+        let mut receiver = sender.subscribe();
+
+        sender
+            .send(FileSystemEvent::overflow(None, None, SystemTime::now()))
+            .await
+            .unwrap();
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            sender.send(FileSystemEvent::overflow(Some(1), None, SystemTime::now())),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        receiver.recv().await.unwrap();
+        sender
+            .send(FileSystemEvent::overflow(Some(1), None, SystemTime::now()))
+            .await
+            .unwrap();
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(
+            event.event_type,
+            FileSystemEventType::Overflow {
+                lost: Some(1),
+                root: None
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn lagged_overflow_carries_first_missed_sequence() {
+        let sender = EventSender::new(
+            2,
+            BackpressurePolicy::DropOldest,
+            EventMask::default(),
+            RootPaths::default(),
+            PathFilter::default(),
+        )
+        .unwrap();
+        let mut receiver = sender.subscribe();
+
+        for lost in 0..5 {
+            sender
+                .send(FileSystemEvent::overflow(
+                    Some(lost),
+                    None,
+                    SystemTime::now(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let lost = match receiver.recv().await {
+            Err(RecvError::Lagged(lost)) => lost,
+            x => panic!("expected lag, got {x:?}"),
+        };
+        assert_eq!(lost, 3);
+        assert_eq!(receiver.lagged(lost).sequence, 0);
+        assert_eq!(receiver.recv().await.unwrap().sequence, 3);
+    }
+
+    #[tokio::test]
+    async fn unbounded_never_lags() {
+        let sender = EventSender::new(
+            1,
+            BackpressurePolicy::Unbounded,
+            EventMask::default(),
+            RootPaths::default(),
+            PathFilter::default(),
+        )
+        .unwrap();
+        let mut receiver = sender.subscribe();
+
+        for lost in 0..100 {
+            sender
+                .send(FileSystemEvent::overflow(
+                    Some(lost),
+                    None,
+                    SystemTime::now(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        for lost in 0..100 {
+            let event = receiver.recv().await.unwrap();
+            assert_eq!(event.sequence, lost);
+            assert_eq!(
+                event.event_type,
+                FileSystemEventType::Overflow {
+                    lost: Some(lost),
+                    root: None
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unselected_event_classes_are_dropped() {
+        let sender = EventSender::new(
+            8,
+            BackpressurePolicy::DropOldest,
+            EventMask::CREATE | EventMask::OPEN,
+            RootPaths::default(),
+            PathFilter::default(),
+        )
+        .unwrap();
+        let mut receiver = sender.subscribe();
+
+        for event_type in [
+            FileSystemEventType::Modify,
+            FileSystemEventType::Open,
+            FileSystemEventType::Access,
+            FileSystemEventType::Create,
+        ] {
+            let target = FileSystemTarget::new("/tmp/file", FileSystemTargetKind::File);
+            sender
+                .send(FileSystemEvent::new(
+                    event_type,
+                    Some(target),
+                    SystemTime::now(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.event_type, FileSystemEventType::Open);
+        assert_eq!(event.sequence, 0);
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.event_type, FileSystemEventType::Create);
+        assert_eq!(event.sequence, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_run_reports_error_then_stopped() {
+        let sender = EventSender::new(
+            1,
+            BackpressurePolicy::DropOldest,
+            EventMask::default(),
+            RootPaths::default(),
+            PathFilter::default(),
+        )
+        .unwrap();
+        let statuses = sender.status_stream();
+
+        let result = sender
+            .run(async { Err(KanshiError::StreamClosedError) })
+            .await;
+        assert!(result.is_err());
+
+        let statuses: Vec<KanshiStatus> = statuses.collect().await;
+        assert!(matches!(
+            statuses.as_slice(),
+            [
+                KanshiStatus::Started,
+                KanshiStatus::Error(KanshiError::StreamClosedError),
+                KanshiStatus::Stopped
+            ]
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+use std::{collections::HashMap, path::PathBuf, pin::Pin, time::Duration};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::{FileSystemEvent, FileSystemEventType};
+
+/// Holds each path's events back until none came for `window`, or `max_wait` after
+/// the first of them, then yields their net effect. Renames, moves and overflows are
+/// not merged. They are yielded at once, after what is pending for their paths, so
+/// every path's events stay in order.
+pub(crate) fn debounce(
+    mut events: Pin<Box<dyn Stream<Item = FileSystemEvent> + Send>>,
+    window: Duration,
+    max_wait: Duration,
+) -> Pin<Box<dyn Stream<Item = FileSystemEvent> + Send>> {
+    Box::pin(stream! {
+        let mut pending = Pending::new(window, max_wait);
+        loop {
+            let next = match pending.next_deadline() {
+                Some(deadline) => tokio::time::timeout_at(deadline, events.next()).await.ok(),
+                None => Some(events.next().await),
+            };
+
+            match next {
+                // Some path has been quiet for long enough, or is due anyway.
+                None => {
+                    for event in pending.due(Instant::now()) {
+                        yield event;
+                    }
+                }
+                Some(None) => {
+                    for event in pending.due(Instant::now() + max_wait) {
+                        yield event;
+                    }
+                    break;
+                }
+                Some(Some(event)) => {
+                    for event in pending.push(event, Instant::now()) {
+                        yield event;
+                    }
+                }
+            }
+        }
+    })
+}
+
+struct Pending {
+    window: Duration,
+    max_wait: Duration,
+    entries: HashMap<PathBuf, Entry>,
+    // Orders events yielded together by when their path first came up.
+    next_order: u64,
+}
+
+struct Entry {
+    event: FileSystemEvent,
+    first_at: Instant,
+    deadline: Instant,
+    order: u64,
+}
+
+/// What two events for the same path amount to.
+enum Merged {
+    Into(FileSystemEventType),
+    Nothing,
+}
+
+impl Pending {
+    fn new(window: Duration, max_wait: Duration) -> Self {
+        Self {
+            window,
+            max_wait,
+            entries: HashMap::new(),
+            next_order: 0,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.entries.values().map(|entry| entry.deadline).min()
+    }
+
+    /// Takes the events that are due at `now`.
+    fn due(&mut self, now: Instant) -> Vec<FileSystemEvent> {
+        let due: Vec<PathBuf> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        self.take(due)
+    }
+
+    /// Adds `event`, returning the events to yield right away.
+    fn push(&mut self, mut event: FileSystemEvent, now: Instant) -> Vec<FileSystemEvent> {
+        let path = match (&event.event_type, &event.target) {
+            (
+                FileSystemEventType::Create
+                | FileSystemEventType::Delete
+                | FileSystemEventType::Modify
+                | FileSystemEventType::CloseWrite
+                | FileSystemEventType::Metadata(_)
+                | FileSystemEventType::Open
+                | FileSystemEventType::Access
+                | FileSystemEventType::CloseNoWrite,
+                Some(target),
+            ) => target.path.clone(),
+            _ => {
+                let mut ready = self.take(passing_paths(&event));
+                ready.push(event);
+                return ready;
+            }
+        };
+
+        let order = self.next_order;
+        let Some(entry) = self.entries.get_mut(&path) else {
+            self.next_order += 1;
+            self.entries.insert(
+                path,
+                Entry {
+                    event,
+                    first_at: now,
+                    deadline: (now + self.window).min(now + self.max_wait),
+                    order,
+                },
+            );
+            return Vec::new();
+        };
+
+        match merge(&entry.event.event_type, &event.event_type) {
+            Merged::Into(event_type) => {
+                // The later event's sequence number, timestamp and target stand for both.
+                event.event_type = event_type;
+                entry.event = event;
+                entry.deadline = (now + self.window).min(entry.first_at + self.max_wait);
+            }
+            Merged::Nothing => {
+                self.entries.remove(&path);
+            }
+        }
+        Vec::new()
+    }
+
+    fn take(&mut self, paths: Vec<PathBuf>) -> Vec<FileSystemEvent> {
+        let mut entries: Vec<Entry> = paths
+            .iter()
+            .filter_map(|path| self.entries.remove(path))
+            .collect();
+        entries.sort_by_key(|entry| entry.order);
+        entries.into_iter().map(|entry| entry.event).collect()
+    }
+}
+
+/// Paths whose pending events have to be yielded before an event that is not merged.
+fn passing_paths(event: &FileSystemEvent) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = event
+        .target
+        .iter()
+        .map(|target| target.path.clone())
+        .collect();
+    match &event.event_type {
+        FileSystemEventType::Rename { from, to } => {
+            paths.extend([from.clone(), to.clone()]);
+        }
+        FileSystemEventType::MovedTo(path) | FileSystemEventType::MovedFrom(path) => {
+            paths.push(path.clone());
+        }
+        _ => {}
+    }
+    paths
+}
+
+/// The net effect of `earlier` followed by `later` on the same path.
+fn merge(earlier: &FileSystemEventType, later: &FileSystemEventType) -> Merged {
+    use FileSystemEventType::*;
+
+    match (earlier, later) {
+        // It came and went within the window.
+        (Create, Delete) => Merged::Nothing,
+        (Create, _) => Merged::Into(Create),
+        // It was replaced.
+        (Delete, Create) => Merged::Into(Modify),
+        (_, Create | Delete) => Merged::Into(later.clone()),
+        (Delete, _) => Merged::Into(Delete),
+        _ if rank(earlier) > rank(later) => Merged::Into(earlier.clone()),
+        _ => Merged::Into(later.clone()),
+    }
+}
+
+// A change to the contents outweighs a change to the metadata, which outweighs a read.
+fn rank(event_type: &FileSystemEventType) -> u8 {
+    match event_type {
+        FileSystemEventType::Modify => 3,
+        FileSystemEventType::CloseWrite => 2,
+        FileSystemEventType::Metadata(_) => 1,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration, time::SystemTime};
+
+    use futures::StreamExt;
+    use tokio::time::Instant;
+
+    use super::debounce;
+    use crate::{FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind};
+
+    fn event(event_type: FileSystemEventType, path: &str) -> FileSystemEvent {
+        FileSystemEvent::new(
+            event_type,
+            Some(FileSystemTarget::new(path, FileSystemTargetKind::File)),
+            SystemTime::now(),
+        )
+    }
+
+    #[tokio::test]
+    async fn merges_each_paths_events_into_their_net_effect() {
+        use FileSystemEventType::*;
+
+        let events = futures::stream::iter([
+            event(Create, "/w/a"),
+            event(Modify, "/w/a"),
+            event(Create, "/w/b"),
+            event(Modify, "/w/b"),
+            event(Delete, "/w/b"),
+            event(CloseWrite, "/w/c"),
+            event(Modify, "/w/c"),
+            event(Delete, "/w/d"),
+            event(Create, "/w/d"),
+            FileSystemEvent::rename(
+                "/w/e",
+                "/w/f",
+                FileSystemTargetKind::File,
+                SystemTime::now(),
+                false,
+            )
+            .remove(0),
+        ]);
+        let merged: Vec<(FileSystemEventType, Option<String>)> = debounce(
+            Box::pin(events),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .map(|event| {
+            let path = event.target.map(|target| target.path.display().to_string());
+            (event.event_type, path)
+        })
+        .collect()
+        .await;
+
+        assert_eq!(
+            merged,
+            [
+                (
+                    Rename {
+                        from: Path::new("/w/e").into(),
+                        to: Path::new("/w/f").into()
+                    },
+                    Some("/w/f".to_owned())
+                ),
+                (Create, Some("/w/a".to_owned())),
+                (Modify, Some("/w/c".to_owned())),
+                (Modify, Some("/w/d".to_owned())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn yields_after_max_wait_while_events_keep_coming() {
+        // A second of events, each within the window of the one before it.
+        let events = async_stream::stream! {
+            for _ in 0..20 {
+                yield event(FileSystemEventType::Modify, "/w/a");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let started = Instant::now();
+        let mut debounced = debounce(
+            Box::pin(events),
+            Duration::from_millis(200),
+            Duration::from_millis(300),
+        );
+
+        let first = debounced.next().await.unwrap();
+        assert_eq!(first.event_type, FileSystemEventType::Modify);
+        assert!(started.elapsed() < Duration::from_millis(800));
+        assert!(debounced.next().await.is_some());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+use crate::KanshiError;
+
+/// Files whose rules are read if the `respect_ignore_files` option is set.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// Ignored along with what the ignore files say, as git does.
+const GIT_DIR: &str = ".git";
+
+/// The `include` and `exclude` globs, matched against paths relative to their watch
+/// root. `*` stays within a path component and `**` spans any number of them.
+/// It also holds the rules of ignore files, if those are respected, in which case
+/// `.git` directories are ignored too.
+#[derive(Clone, Default)]
+pub(crate) struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    ignore_files: Option<IgnoreFiles>,
+}
+
+/// Rules read from `.git/info/exclude`, `.gitignore` and `.ignore`, by the canonical
+/// path of the directory they apply to. Rules closer to a path take precedence.
+#[derive(Clone, Default)]
+struct IgnoreFiles {
+    inner: Arc<Mutex<IgnoreRules>>,
+}
+
+#[derive(Default)]
+struct IgnoreRules {
+    by_dir: HashMap<PathBuf, Gitignore>,
+    // Directories left unmarked because they were ignored, with their depth below
+    // their watch root. They are marked if a change to the rules stops ignoring them.
+    skipped: HashMap<PathBuf, usize>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(
+        include: &[String],
+        exclude: &[String],
+        respect_ignore_files: bool,
+    ) -> Result<PathFilter, KanshiError> {
+        Ok(PathFilter {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            ignore_files: respect_ignore_files.then(IgnoreFiles::default),
+        })
+    }
+
+    /// Whether the directory at `path`, `depth` levels below its watch root, is
+    /// excluded. Excluded directories are not marked, and neither is anything below them.
+    /// Ignore rules are only looked at if `path` is a directory, as only directories
+    /// are marked again once the rules stop ignoring them.
+    pub(crate) fn excludes(&self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        let components: Vec<_> = path.components().collect();
+        let relative: PathBuf = components[components.len().saturating_sub(depth)..]
+            .iter()
+            .collect();
+        if self.excludes_relative(&relative) || self.in_git_dir(&relative) {
+            return true;
+        }
+
+        // A directory passed to watch() is watched even if it is ignored.
+        let Some(ignore_files) = self.ignore_files.as_ref().filter(|_| depth > 0 && is_dir) else {
+            return false;
+        };
+        let mut rules = ignore_files.inner.lock().unwrap();
+        let Some(canonical) = canonical(path) else {
+            return false;
+        };
+        let ignored = rules.ignores(&canonical, is_dir);
+        if ignored {
+            rules.skipped.insert(canonical, depth);
+        }
+        ignored
+    }
+
+    /// Whether the ignore files say `path`, which is below a canonical watch root,
+    /// is ignored.
+    pub(crate) fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(ignore_files) = &self.ignore_files else {
+            return false;
+        };
+        let rules = ignore_files.inner.lock().unwrap();
+        !rules.by_dir.is_empty() && rules.ignores(path, is_dir)
+    }
+
+    /// Reads the ignore files in `dir`, and at a watch root, the ones in the
+    /// directories above it up to the root of its git repository.
+    pub(crate) fn load_ignore_files(&self, dir: &Path, depth: usize) {
+        let Some(ignore_files) = &self.ignore_files else {
+            return;
+        };
+        let mut rules = ignore_files.inner.lock().unwrap();
+        let Ok(dir) = fs::canonicalize(dir) else {
+            return;
+        };
+        rules.load(&dir);
+
+        if depth == 0 {
+            let above: Vec<&Path> = dir.ancestors().skip(1).collect();
+            if let Some(repository) = above.iter().position(|dir| dir.join(".git").exists()) {
+                for dir in &above[..=repository] {
+                    rules.load(dir);
+                }
+            }
+        }
+    }
+
+    /// Reads the rules again if `path` is an ignore file. Returns the skipped
+    /// directories they no longer ignore, with their depth, for the engine to mark.
+    pub(crate) fn reload_ignore_files(&self, path: &Path) -> Vec<(PathBuf, usize)> {
+        let Some(ignore_files) = &self.ignore_files else {
+            return Vec::new();
+        };
+        let is_ignore_file = path
+            .file_name()
+            .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file));
+        let dir = if is_ignore_file {
+            path.parent()
+        } else if path.ends_with(".git/info/exclude") {
+            path.ancestors().nth(3)
+        } else {
+            None
+        };
+        let Some(dir) = dir.and_then(|dir| fs::canonicalize(dir).ok()) else {
+            return Vec::new();
+        };
+
+        let mut rules = ignore_files.inner.lock().unwrap();
+        rules.load(&dir);
+        let unignored: Vec<(PathBuf, usize)> = rules
+            .skipped
+            .iter()
+            .filter(|(skipped, _)| {
+                skipped.starts_with(&dir) && skipped.is_dir() && !rules.ignores(skipped, true)
+            })
+            .map(|(skipped, depth)| (skipped.clone(), *depth))
+            .collect();
+        for (skipped, _) in &unignored {
+            rules.skipped.remove(skipped);
+        }
+        unignored
+    }
+
+    /// Whether events about `relative`, a path below its watch root, reach subscribers.
+    /// Events about the root itself always do.
+    pub(crate) fn wants(&self, relative: &Path) -> bool {
+        if relative.as_os_str().is_empty() {
+            return true;
+        }
+        !self.excludes_relative(relative)
+            && !self.in_git_dir(relative)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative))
+    }
+
+    fn in_git_dir(&self, relative: &Path) -> bool {
+        self.ignore_files.is_some()
+            && relative
+                .components()
+                .any(|component| component.as_os_str() == GIT_DIR)
+    }
+
+    // Anything below an excluded directory is excluded with it.
+    fn excludes_relative(&self, relative: &Path) -> bool {
+        let Some(exclude) = &self.exclude else {
+            return false;
+        };
+        relative
+            .ancestors()
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| exclude.is_match(ancestor))
+    }
+}
+
+impl IgnoreRules {
+    /// Replaces the rules of `dir`, which is canonical, with what its files say now.
+    fn load(&mut self, dir: &Path) {
+        let mut builder = GitignoreBuilder::new(dir);
+        let files = [dir.join(".git/info/exclude")]
+            .into_iter()
+            .chain(IGNORE_FILES.iter().map(|file| dir.join(file)));
+        for file in files.filter(|file| file.is_file()) {
+            // A bad line is skipped, the rest of the file still applies.
+            let _ = builder.add(file);
+        }
+
+        match builder.build() {
+            Ok(gitignore) if !gitignore.is_empty() => {
+                self.by_dir.insert(dir.to_path_buf(), gitignore);
+            }
+            _ => {
+                self.by_dir.remove(dir);
+            }
+        }
+    }
+
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            let Some(gitignore) = self.by_dir.get(dir) else {
+                continue;
+            };
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// `path` with the directory it is in canonicalized, so it matches the rules whichever
+/// form of the path an engine reports. `path` itself may be gone already.
+fn canonical(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let parent = fs::canonicalize(path.parent()?).ok()?;
+    Some(parent.join(name))
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, KanshiError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| KanshiError::InvalidParameter(format!("invalid glob {pattern:?}: {e}")))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| KanshiError::InvalidParameter(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::PathFilter;
+
+    #[test]
+    fn excludes_subtrees_and_includes_matching_files() {
+        let filter = PathFilter::new(
+            &["**/*.rs".to_owned()],
+            &["**/node_modules".to_owned(), "target".to_owned()],
+            false,
+        )
+        .unwrap();
+
+        assert!(filter.wants(Path::new("src/lib.rs")));
+        assert!(!filter.wants(Path::new("src/lib.js")));
+        assert!(!filter.wants(Path::new("web/node_modules/a/index.rs")));
+        assert!(!filter.wants(Path::new("target/debug/build.rs")));
+        assert!(filter.wants(Path::new("src/target/x.rs")));
+        assert!(filter.wants(Path::new("")));
+
+        assert!(filter.excludes(Path::new("/w/web/node_modules"), 2, true));
+        assert!(!filter.excludes(Path::new("/w/src/target"), 2, true));
+        assert!(filter.excludes(Path::new("/w/target"), 1, true));
+        assert!(!filter.excludes(Path::new("/w"), 0, true));
+
+        assert!(PathFilter::new(&["a[".to_owned()], &[], false).is_err());
+    }
+}
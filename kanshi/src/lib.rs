@@ -1,9 +1,24 @@
+mod atomic_saves;
+mod batch;
+mod channel;
+mod debounce;
+mod filter;
+mod listings;
+#[cfg(unix)]
+mod metadata;
 mod platforms;
+mod watches;
 
 pub use platforms::*;
 
-use std::{ffi::OsString, io, pin::Pin};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
 
+use bitflags::bitflags;
 use thiserror::Error;
 
 #[cfg(unix)]
@@ -17,8 +32,8 @@ pub enum KanshiError {
     #[error("invalid command supplied: {0}")]
     InvalidCommand(String),
 
-    #[error("file system error {0}")]
-    FileSystemError(String),
+    #[error("file system error: {0}")]
+    FileSystemError(FileSystemError),
 
     #[error("the file system listener was closed")]
     StreamClosedError,
@@ -32,26 +47,341 @@ pub enum KanshiError {
 
 impl From<io::Error> for KanshiError {
     fn from(value: io::Error) -> Self {
-        KanshiError::FileSystemError(value.to_string())
+        KanshiError::FileSystemError(FileSystemError::new(
+            FileSystemOperation::Other,
+            None,
+            value,
+        ))
     }
 }
 
-
 #[cfg(unix)]
 impl From<Errno> for KanshiError {
     fn from(value: Errno) -> Self {
-        KanshiError::FileSystemError(value.to_string())
+        KanshiError::from(io::Error::from(value))
+    }
+}
+
+impl KanshiError {
+    pub(crate) fn file_system(
+        operation: FileSystemOperation,
+        path: Option<&Path>,
+        error: impl Into<io::Error>,
+    ) -> KanshiError {
+        KanshiError::FileSystemError(FileSystemError::new(operation, path, error.into()))
+    }
+}
+
+/// The step that failed with a `FileSystemError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileSystemOperation {
+    /// Creating the engine's kernel handles.
+    Init,
+    /// Adding a watch or mark to a path.
+    Mark,
+    /// Removing a watch or mark from a path.
+    Unmark,
+    /// Walking a watched directory tree.
+    Traverse,
+    ReadEvents,
+    EpollWait,
+    /// Turning a fanotify file handle back into a path.
+    ResolveHandle,
+    /// Starting the engine's event stream.
+    Start,
+    Other,
+}
+
+impl fmt::Display for FileSystemOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self {
+            FileSystemOperation::Init => "init",
+            FileSystemOperation::Mark => "mark",
+            FileSystemOperation::Unmark => "unmark",
+            FileSystemOperation::Traverse => "traverse",
+            FileSystemOperation::ReadEvents => "read_events",
+            FileSystemOperation::EpollWait => "epoll_wait",
+            FileSystemOperation::ResolveHandle => "resolve_handle",
+            FileSystemOperation::Start => "start",
+            FileSystemOperation::Other => "other",
+        };
+        f.write_str(operation)
+    }
+}
+
+/// An OS error, along with the operation and path it happened on.
+/// Match on `kind` or `raw_os_error` to tell errors such as `ENOSPC` (out of
+/// watches), `EACCES` and `ENOENT` apart.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub struct FileSystemError {
+    pub operation: FileSystemOperation,
+    pub kind: io::ErrorKind,
+    pub raw_os_error: Option<i32>,
+    pub path: Option<PathBuf>,
+    message: String,
+}
+
+impl FileSystemError {
+    pub(crate) fn new(
+        operation: FileSystemOperation,
+        path: Option<&Path>,
+        error: io::Error,
+    ) -> FileSystemError {
+        FileSystemError {
+            operation,
+            kind: error.kind(),
+            raw_os_error: error.raw_os_error(),
+            path: path.map(Path::to_path_buf),
+            message: error.to_string(),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn errno(&self) -> Option<Errno> {
+        self.raw_os_error.map(Errno::from_raw)
+    }
+}
+
+impl fmt::Display for FileSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(
+                f,
+                "{} failed on {}: {}",
+                self.operation,
+                path.display(),
+                self.message
+            )
+        } else {
+            write!(f, "{} failed: {}", self.operation, self.message)
+        }
+    }
+}
+
+/// Lifecycle notifications, delivered to every subscriber of `get_status_stream()`.
+#[derive(Clone, Debug)]
+pub enum KanshiStatus {
+    /// `start()` is running and events are being read.
+    Started,
+    /// A problem Kanshi recovered from, such as a file handle that could not be
+    /// resolved. Events may have been skipped.
+    Warning(KanshiError),
+    /// `start()` failed. It is followed by `Stopped`.
+    Error(KanshiError),
+    /// `start()` returned, either after `close()` or an `Error`.
+    Stopped,
+}
+
+/// Default number of events buffered per subscriber.
+pub const DEFAULT_EVENT_BUFFER_CAPACITY: usize = 32;
+
+/// What Kanshi does when a subscriber falls behind and its buffer is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Drop the oldest buffered events. The lagging subscriber receives an
+    /// `Overflow` event with the number of events it missed.
+    #[default]
+    DropOldest,
+    /// Stop reading from the kernel until every subscriber has room again.
+    /// Events may then be lost to a kernel queue overflow instead.
+    Block,
+    /// Buffer events without limit for every subscriber. The buffer capacity is ignored.
+    Unbounded,
+}
+
+impl BackpressurePolicy {
+    pub fn from(string: &str) -> Result<BackpressurePolicy, KanshiError> {
+        match string {
+            "drop_oldest" => Ok(BackpressurePolicy::DropOldest),
+            "block" => Ok(BackpressurePolicy::Block),
+            "unbounded" => Ok(BackpressurePolicy::Unbounded),
+            _ => Err(KanshiError::InvalidParameter(
+                "Invalid backpressure policy. Allowed values are: 'drop_oldest', 'block', 'unbounded'."
+                    .to_owned(),
+            )),
+        }
     }
 }
 
+/// How far below a watched directory Kanshi follows subdirectories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecursionMode {
+    /// Only the watched directory itself, reporting events for its direct entries.
+    NonRecursive,
+    /// The whole tree, including directories created later.
+    #[default]
+    Recursive,
+    /// Subdirectories at most this many levels below the watched directory.
+    /// `MaxDepth(0)` is the same as `NonRecursive`.
+    MaxDepth(usize),
+}
+
+impl RecursionMode {
+    /// Whether a directory `depth` levels below the watched one is watched itself.
+    pub(crate) fn includes(self, depth: usize) -> bool {
+        match self {
+            RecursionMode::NonRecursive => depth == 0,
+            RecursionMode::Recursive => true,
+            RecursionMode::MaxDepth(max_depth) => depth <= max_depth,
+        }
+    }
+}
+
+/// How the root of a watch, and so every event path below it, is reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootPaths {
+    /// The path passed to `watch()`, made absolute without resolving symlinks.
+    #[default]
+    AsRequested,
+    /// The canonical path of the root, with every symlink resolved.
+    Canonical,
+}
+
+impl RootPaths {
+    pub fn from(string: &str) -> Result<RootPaths, KanshiError> {
+        match string {
+            "as_requested" => Ok(RootPaths::AsRequested),
+            "canonical" => Ok(RootPaths::Canonical),
+            _ => Err(KanshiError::InvalidParameter(
+                "Invalid root paths. Allowed values are: 'as_requested', 'canonical'.".to_owned(),
+            )),
+        }
+    }
+}
+
+bitflags! {
+    /// Classes of events to subscribe to. Only the selected classes are requested
+    /// from the kernel where the engine allows it, and only they are reported.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EventMask: u32 {
+        const CREATE = 1 << 0;
+        const DELETE = 1 << 1;
+        const MODIFY = 1 << 2;
+        /// Renames and moves.
+        const MOVE = 1 << 3;
+        const CLOSE_WRITE = 1 << 4;
+        const CLOSE_NOWRITE = 1 << 5;
+        const OPEN = 1 << 6;
+        const ACCESS = 1 << 7;
+        /// Permission, ownership, timestamp and extended attribute changes.
+        const ATTRIB = 1 << 8;
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        EventMask::CREATE
+            | EventMask::DELETE
+            | EventMask::MODIFY
+            | EventMask::MOVE
+            | EventMask::CLOSE_WRITE
+            | EventMask::ATTRIB
+    }
+}
+
+impl EventMask {
+    pub fn from(string: &str) -> Result<EventMask, KanshiError> {
+        match string {
+            "create" => Ok(EventMask::CREATE),
+            "delete" => Ok(EventMask::DELETE),
+            "modify" => Ok(EventMask::MODIFY),
+            "move" => Ok(EventMask::MOVE),
+            "close_write" => Ok(EventMask::CLOSE_WRITE),
+            "close_nowrite" => Ok(EventMask::CLOSE_NOWRITE),
+            "open" => Ok(EventMask::OPEN),
+            "access" => Ok(EventMask::ACCESS),
+            "attrib" => Ok(EventMask::ATTRIB),
+            _ => Err(KanshiError::InvalidParameter(
+                "Invalid event class. Allowed values are: 'create', 'delete', 'modify', 'move', 'close_write', 'close_nowrite', 'open', 'access', 'attrib'."
+                    .to_owned(),
+            )),
+        }
+    }
+
+    /// Whether an event of this type should be reported. Events that do not belong to
+    /// a class, such as `Overflow`, are always reported.
+    pub(crate) fn wants(&self, event_type: &FileSystemEventType) -> bool {
+        let class = match event_type {
+            FileSystemEventType::Create => EventMask::CREATE,
+            FileSystemEventType::Delete => EventMask::DELETE,
+            FileSystemEventType::Modify => EventMask::MODIFY,
+            FileSystemEventType::CloseWrite => EventMask::CLOSE_WRITE,
+            FileSystemEventType::CloseNoWrite => EventMask::CLOSE_NOWRITE,
+            FileSystemEventType::Open => EventMask::OPEN,
+            FileSystemEventType::Access => EventMask::ACCESS,
+            FileSystemEventType::Metadata(_) => EventMask::ATTRIB,
+            FileSystemEventType::Move
+            | FileSystemEventType::MovedTo(_)
+            | FileSystemEventType::MovedFrom(_)
+            | FileSystemEventType::Rename { .. } => EventMask::MOVE,
+            FileSystemEventType::Overflow { .. } | FileSystemEventType::Unknown => return true,
+        };
+
+        self.intersects(class)
+    }
+}
+
+bitflags! {
+    /// Attribute classes carried by a `Metadata` event.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct MetadataChange: u8 {
+        /// Permission bits.
+        const MODE = 1 << 0;
+        /// Owning user or group.
+        const OWNER = 1 << 1;
+        /// Access or modification time.
+        const TIMES = 1 << 2;
+        /// Extended attributes.
+        const XATTR = 1 << 3;
+    }
+}
+
+/// Which way an item crossed the boundary of the watched tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveDirection {
+    /// Moved in from outside the watched tree.
+    In,
+    /// Moved out of the watched tree.
+    Out,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum FileSystemEventType {
     Create,
     Delete,
     Modify,
+    /// A file that was opened for writing has been closed, so its contents are complete.
+    /// Not reported by FSEvents.
+    CloseWrite,
+    /// A file or directory that was opened read-only has been closed.
+    CloseNoWrite,
+    Open,
+    /// A file was read.
+    Access,
+    /// Permissions, ownership, timestamps or extended attributes changed.
+    /// Carries which of them changed when it could be determined.
+    Metadata(Option<MetadataChange>),
+    /// An item was moved into or out of the watched tree, or a watched item was
+    /// itself moved. `FileSystemEvent::move_direction` says which way, when known.
     Move,
-    MovedTo(OsString),
-    MovedFrom(OsString),
+    MovedTo(PathBuf),
+    MovedFrom(PathBuf),
+    /// An item was renamed or moved within the watched tree. `target.path` is the
+    /// same as `to`.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Events were lost, either because the kernel queue overflowed or because a
+    /// subscriber fell behind the broadcast channel. Any state derived from earlier
+    /// events should be rebuilt by rescanning.
+    ///
+    /// `lost` is the number of dropped events when it is known. `root` is the watch
+    /// root that needs rescanning, or `None` if every watched root is affected.
+    Overflow {
+        lost: Option<u64>,
+        root: Option<PathBuf>,
+    },
     Unknown,
 }
 
@@ -63,7 +393,14 @@ impl ToString for FileSystemEventType {
             FileSystemEventType::Create => "create",
             FileSystemEventType::Delete => "delete",
             FileSystemEventType::Modify => "modify",
+            FileSystemEventType::CloseWrite => "close_write",
+            FileSystemEventType::CloseNoWrite => "close_nowrite",
+            FileSystemEventType::Open => "open",
+            FileSystemEventType::Access => "access",
+            FileSystemEventType::Metadata(_) => "metadata",
             FileSystemEventType::Move => "move",
+            FileSystemEventType::Rename { .. } => "rename",
+            FileSystemEventType::Overflow { .. } => "overflow",
             FileSystemEventType::Unknown => "unknown",
         }
         .to_owned()
@@ -74,18 +411,179 @@ impl ToString for FileSystemEventType {
 pub enum FileSystemTargetKind {
     Directory,
     File,
+    Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
+    /// The item was gone before its type could be looked up, for example after a delete.
+    Unknown,
+}
+
+impl fmt::Display for FileSystemTargetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            FileSystemTargetKind::Directory => "directory",
+            FileSystemTargetKind::File => "file",
+            FileSystemTargetKind::Symlink => "symlink",
+            FileSystemTargetKind::Socket => "socket",
+            FileSystemTargetKind::Fifo => "fifo",
+            FileSystemTargetKind::BlockDevice => "block_device",
+            FileSystemTargetKind::CharDevice => "char_device",
+            FileSystemTargetKind::Unknown => "unknown",
+        };
+        f.write_str(kind)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FileSystemTarget {
     pub kind: FileSystemTargetKind,
-    pub path: OsString,
+    /// Absolute path of the item, starting with the root of its watch as the
+    /// `root_paths` option reports it.
+    pub path: PathBuf,
+    /// `path` relative to the root of its watch. Empty for the root itself, and
+    /// `None` if the path is outside of every watch.
+    pub relative_path: Option<PathBuf>,
+}
+
+impl FileSystemTarget {
+    /// The relative path is filled in once the event is sent to subscribers.
+    pub(crate) fn new(path: impl Into<PathBuf>, kind: FileSystemTargetKind) -> FileSystemTarget {
+        FileSystemTarget {
+            kind,
+            path: path.into(),
+            relative_path: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FileSystemEvent {
     pub event_type: FileSystemEventType,
     pub target: Option<FileSystemTarget>,
+    /// Per-instance sequence number. It increases by one for every event Kanshi
+    /// delivers, so gaps mean events were lost.
+    pub sequence: u64,
+    /// Wall-clock time at which the engine read the event from the kernel.
+    pub timestamp: SystemTime,
+    /// Id of the `WatchHandle` whose tree the event happened in. If watched trees
+    /// overlap, this is the handle with the deepest root.
+    pub watch_id: Option<u64>,
+    /// Which way the item of a `Move` event went, if it is known.
+    pub move_direction: Option<MoveDirection>,
+}
+
+impl FileSystemEvent {
+    /// The sequence number is assigned once the event is sent to subscribers.
+    pub(crate) fn new(
+        event_type: FileSystemEventType,
+        target: Option<FileSystemTarget>,
+        timestamp: SystemTime,
+    ) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target,
+            sequence: 0,
+            timestamp,
+            watch_id: None,
+            move_direction: None,
+        }
+    }
+
+    /// A `Move` of an item into or out of the watched tree.
+    pub(crate) fn moved(
+        direction: MoveDirection,
+        target: FileSystemTarget,
+        timestamp: SystemTime,
+    ) -> FileSystemEvent {
+        FileSystemEvent {
+            move_direction: Some(direction),
+            ..FileSystemEvent::new(FileSystemEventType::Move, Some(target), timestamp)
+        }
+    }
+
+    /// Builds the events reported for an item moved from `from` to `to`. This is a single
+    /// `Rename` event, or the legacy `MovedTo`/`MovedFrom` pair if `split_renames` is set.
+    pub(crate) fn rename(
+        from: impl Into<PathBuf>,
+        to: impl Into<PathBuf>,
+        kind: FileSystemTargetKind,
+        timestamp: SystemTime,
+        split_renames: bool,
+    ) -> Vec<FileSystemEvent> {
+        let (from, to) = (from.into(), to.into());
+        if split_renames {
+            vec![
+                FileSystemEvent::new(
+                    FileSystemEventType::MovedTo(to.clone()),
+                    Some(FileSystemTarget::new(from.clone(), kind.clone())),
+                    timestamp,
+                ),
+                FileSystemEvent::new(
+                    FileSystemEventType::MovedFrom(from),
+                    Some(FileSystemTarget::new(to, kind)),
+                    timestamp,
+                ),
+            ]
+        } else {
+            vec![FileSystemEvent::new(
+                FileSystemEventType::Rename {
+                    from,
+                    to: to.clone(),
+                },
+                Some(FileSystemTarget::new(to, kind)),
+                timestamp,
+            )]
+        }
+    }
+
+    pub(crate) fn overflow(
+        lost: Option<u64>,
+        root: Option<PathBuf>,
+        timestamp: SystemTime,
+    ) -> FileSystemEvent {
+        FileSystemEvent::new(FileSystemEventType::Overflow { lost, root }, None, timestamp)
+    }
+}
+
+/// The paths that changed between two quiet periods, each listed once with its net
+/// change. Paths are sorted, and reads don't count as changes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChangeBatch {
+    pub added: Vec<PathBuf>,
+    /// Also lists renamed items whose contents changed, under their new path.
+    pub modified: Vec<PathBuf>,
+    /// Items removed, under the path they had before the batch.
+    pub removed: Vec<PathBuf>,
+    /// Items that existed before the batch and were renamed, as `(from, to)`.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Events were lost, so the batch may be missing changes. Rescan to be sure.
+    pub overflow: bool,
+}
+
+impl ChangeBatch {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && !self.overflow
+    }
+}
+
+/// A directory tree passed to `watch()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchHandle {
+    /// Unique within a Kanshi instance. Events carry it as `watch_id`.
+    pub id: u64,
+    /// Absolute path of the watched directory, canonicalized if the `root_paths`
+    /// option asks for it.
+    pub root: PathBuf,
+    /// Number of directories marked for this tree, including directories created
+    /// after `watch()` returned. FSEvents watches the whole tree with a single stream.
+    pub directories: usize,
+    pub engine: KanshiEngines,
 }
 
 pub trait KanshiImpl<Opts>: Clone + Send + Sync {
@@ -95,18 +593,66 @@ pub trait KanshiImpl<Opts>: Clone + Send + Sync {
     where
         Self: Sized + Clone;
 
-    /// Watches a new directory.
+    /// Watches a new directory, or on Linux a single file.
+    /// Warning: This method blocks the thread until its finished!
+    fn watch(
+        &self,
+        dir: impl AsRef<Path>,
+    ) -> impl futures::Future<Output = Result<WatchHandle, KanshiError>>;
+
+    /// Stops watching a directory and everything below it.
     /// Warning: This method blocks the thread until its finished!
-    fn watch(&self, dir: &str) -> impl futures::Future<Output = Result<(), KanshiError>>;
+    fn unwatch(
+        &self,
+        dir: impl AsRef<Path>,
+    ) -> impl futures::Future<Output = Result<(), KanshiError>>;
+
+    /// Lists the directory trees currently being watched.
+    /// This method does not block and is safe to use in an async context.
+    fn watches(&self) -> Vec<WatchHandle>;
 
     /// Get a new stream where events can be received.
     /// This method does not block and is safe to use in an async context.
     fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>>;
 
+    /// Same as `get_events_stream()`, except each path's events are held back until
+    /// none came for `debounce`, or `max_wait` after the first of them if they keep
+    /// coming, then merged into their net effect. A create followed by modifies is a
+    /// create, and a create followed by a delete is nothing at all.
+    /// It must be polled from within a Tokio runtime.
+    fn get_debounced_events_stream(
+        &self,
+        debounce: Duration,
+        max_wait: Duration,
+    ) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
+        debounce::debounce(self.get_events_stream(), debounce, max_wait)
+    }
+
+    /// Get a new stream of `ChangeBatch`es. A batch is yielded once no event came for
+    /// `quiet`, or `max_wait` after its first event if events keep coming.
+    /// It must be polled from within a Tokio runtime.
+    fn get_change_batch_stream(
+        &self,
+        quiet: Duration,
+        max_wait: Duration,
+    ) -> Pin<Box<dyn futures::Stream<Item = ChangeBatch> + Send>> {
+        batch::batch(self.get_events_stream(), quiet, max_wait)
+    }
+
+    /// Get a new stream of lifecycle notifications. It ends after `KanshiStatus::Stopped`.
+    /// Subscribe before calling `start()` to be sure to receive `KanshiStatus::Started`.
+    fn get_status_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStatus> + Send>>;
+
     /// Start listening for events. Kanshi will ignore all events until this method is run.
-    /// Warning: This method blocks the thread until its finished!
+    /// The future runs until `close()` is called. On Linux it only wakes when there are
+    /// events to read, so it is safe to run on a current-thread runtime.
     fn start(&self) -> impl futures::Future<Output = Result<(), KanshiError>>;
 
+    /// Resolves once `start()` is listening, so every change made from then on is
+    /// reported. Fails if Kanshi stopped before it got there, or has stopped since.
+    /// This method does not block and is safe to use in an async context.
+    fn ready(&self) -> impl futures::Future<Output = Result<(), KanshiError>>;
+
     fn close(&self) -> bool;
 }
 
@@ -120,7 +666,7 @@ mod tests {
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
     async fn main() {
-        let kanshi = Kanshi::new(KanshiOptions { force_engine: None });
+        let kanshi = Kanshi::new(KanshiOptions::default());
         if let Err(e) = kanshi {
             panic!("{e}");
         }
@@ -164,12 +710,17 @@ mod tests {
 #[cfg(target_os = "linux")]
 mod tests {
 
-    use crate::{Kanshi, KanshiImpl, KanshiOptions};
+    use std::{io, path::Path};
+
+    use crate::{
+        EventMask, FileSystemEventType, FileSystemOperation, Kanshi, KanshiEngines, KanshiError,
+        KanshiImpl, KanshiOptions, MoveDirection, RecursionMode,
+    };
     use futures::StreamExt;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
     async fn main() {
-        let kanshi = Kanshi::new(KanshiOptions { force_engine: None });
+        let kanshi = Kanshi::new(KanshiOptions::default());
         if let Err(e) = kanshi {
             panic!("{e}");
         }
@@ -205,4 +756,411 @@ mod tests {
         println!("closed");
         tokio::time::sleep(std::time::Duration::from_secs(10)).await;
     }
+
+    #[tokio::test]
+    async fn watching_missing_directory_reports_not_found() {
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let err = kanshi.watch("/kanshi-does-not-exist").await.unwrap_err();
+
+        let KanshiError::FileSystemError(e) = err else {
+            panic!("expected a file system error, got {err:?}");
+        };
+        assert_eq!(e.kind, io::ErrorKind::NotFound);
+        assert_eq!(e.errno(), Some(nix::errno::Errno::ENOENT));
+        assert_eq!(e.operation, FileSystemOperation::Mark);
+        assert_eq!(e.path.as_deref(), Some(Path::new("/kanshi-does-not-exist")));
+    }
+
+    #[tokio::test]
+    async fn unwatch_removes_whole_tree() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+        assert_eq!(handle.root, root);
+        assert_eq!(handle.directories, 2);
+        assert_eq!(kanshi.watches(), vec![handle]);
+
+        kanshi.unwatch(&root).await.unwrap();
+        assert!(kanshi.watches().is_empty());
+        let err = kanshi.unwatch(&root).await.unwrap_err();
+
+        let KanshiError::FileSystemError(e) = err else {
+            panic!("expected a file system error, got {err:?}");
+        };
+        assert_eq!(e.kind, io::ErrorKind::NotFound);
+        assert_eq!(e.operation, FileSystemOperation::Unmark);
+    }
+
+    #[tokio::test]
+    async fn watch_single_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("app.toml");
+        std::fs::write(&file, "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let handle = kanshi.watch(&file).await.unwrap();
+        assert_eq!(handle.root, file);
+        assert_eq!(handle.directories, 0);
+
+        kanshi.unwatch(&file).await.unwrap();
+        assert!(kanshi.watches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unwatch_keeps_nested_watches() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+        let nested = kanshi.watch(root.join("a")).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        kanshi.unwatch(&root).await.unwrap();
+        assert_eq!(kanshi.watches(), vec![nested.clone()]);
+
+        std::fs::write(root.join("x"), "").unwrap();
+        std::fs::write(root.join("a/b/y"), "").unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.target.unwrap().path, root.join("a/b/y"));
+        assert_eq!(event.watch_id, Some(nested.id));
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unwatch_file_keeps_other_files_in_its_directory() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a"), "").unwrap();
+        std::fs::write(root.join("b"), "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(root.join("a")).await.unwrap();
+        let b = kanshi.watch(root.join("b")).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        kanshi.unwatch(root.join("a")).await.unwrap();
+        assert_eq!(kanshi.watches(), vec![b.clone()]);
+
+        std::fs::write(root.join("a"), "a").unwrap();
+        std::fs::write(root.join("b"), "b").unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.target.unwrap().path, root.join("b"));
+        assert_eq!(event.watch_id, Some(b.id));
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_file_rejects_a_mask_without_file_events() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("app.toml");
+        std::fs::write(&file, "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            event_mask: EventMask::CREATE | EventMask::DELETE,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            kanshi.watch(&file).await,
+            Err(KanshiError::InvalidParameter(_))
+        ));
+        assert!(kanshi.watches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watch_non_utf8_directory() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join(OsStr::from_bytes(b"caf\xe9"));
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+        assert_eq!(handle.root, root);
+        assert_eq!(handle.directories, 2);
+
+        kanshi.unwatch(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_stops_at_max_depth() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        std::fs::create_dir_all(root.join("d")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            recursion: RecursionMode::MaxDepth(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+
+        assert_eq!(handle.directories, 3);
+    }
+
+    #[tokio::test]
+    async fn watch_skips_excluded_directories() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("web/node_modules/pkg")).unwrap();
+        std::fs::create_dir_all(root.join("target/debug")).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            exclude: vec!["**/node_modules".to_owned(), "target".to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+
+        // The root, web and src.
+        assert_eq!(handle.directories, 3);
+    }
+
+    #[tokio::test]
+    async fn follows_symlinks_without_looping() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path().to_path_buf();
+        let root = base.join("root");
+        std::fs::create_dir_all(base.join("store/pkg/lib")).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(base.join("store/pkg"), root.join("pkg")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("loop")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+
+        // The root, pkg and pkg/lib. The link back to the root is skipped.
+        assert_eq!(handle.directories, 3);
+    }
+
+    // Runs on a current-thread runtime, which the reader must not block.
+    #[tokio::test]
+    async fn ready_resolves_once_listening() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(&root).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        std::fs::write(root.join("file"), "").unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.target.unwrap().path, root.join("file"));
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+        assert!(kanshi.ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pairs_moves_and_reports_unpaired_ones() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path().to_path_buf();
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a"), "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        std::fs::rename(root.join("a"), root.join("b")).unwrap();
+        let event = events.next().await.unwrap();
+        assert!(matches!(event.event_type, FileSystemEventType::Rename { .. }));
+
+        std::fs::rename(root.join("b"), base.join("b")).unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.event_type, FileSystemEventType::Move);
+        assert_eq!(event.move_direction, Some(MoveDirection::Out));
+        assert_eq!(event.target.unwrap().path, root.join("b"));
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn renamed_directories_report_their_new_path() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("a/nested")).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        // b is gone by the time its rename is read.
+        std::fs::rename(root.join("a"), root.join("b")).unwrap();
+        std::fs::rename(root.join("b"), root.join("c")).unwrap();
+        std::fs::write(root.join("c/nested/x"), "").unwrap();
+        while events.next().await.unwrap().target.unwrap().path != root.join("c/nested/x") {}
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn fanotify_marks_directories_moved_in_and_unmarks_those_moved_out() {
+        // Fanotify needs CAP_SYS_ADMIN.
+        let Ok(kanshi) = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Fanotify),
+            ..Default::default()
+        }) else {
+            return;
+        };
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(temp.path().join("a/b")).unwrap();
+
+        kanshi.watch(&root).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        std::fs::rename(temp.path().join("a"), root.join("a")).unwrap();
+        events.next().await.unwrap();
+        assert_eq!(kanshi.watches()[0].directories, 3);
+
+        std::fs::rename(root.join("a"), temp.path().join("a")).unwrap();
+        events.next().await.unwrap();
+        assert_eq!(kanshi.watches()[0].directories, 1);
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn respects_ignore_files_and_reloads_them() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(root.join("build")).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join(".gitignore"), "build/\n*.log\n").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            respect_ignore_files: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let handle = kanshi.watch(&root).await.unwrap();
+        assert_eq!(handle.directories, 2);
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        std::fs::write(root.join("src/a.log"), "").unwrap();
+        std::fs::write(root.join(".git/index"), "").unwrap();
+        std::fs::write(root.join("src/a.rs"), "").unwrap();
+        let event = events.next().await.unwrap();
+        assert_eq!(event.target.unwrap().path, root.join("src/a.rs"));
+
+        // build/ is marked once the rules stop ignoring it.
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        while events.next().await.unwrap().target.unwrap().path != root.join(".gitignore") {}
+        std::fs::write(root.join("build/out"), "").unwrap();
+        while events.next().await.unwrap().target.unwrap().path != root.join("build/out") {}
+        assert_eq!(kanshi.watches()[0].directories, 3);
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignored_files_are_not_marked_once_the_rules_change() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("debug.log"), "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            respect_ignore_files: true,
+            ..Default::default()
+        })
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+        let mut events = kanshi.get_events_stream();
+
+        let kan = kanshi.clone();
+        let run = tokio::spawn(async move { kan.start().await });
+        kanshi.ready().await.unwrap();
+
+        std::fs::write(root.join(".gitignore"), "").unwrap();
+        std::fs::write(root.join("a"), "").unwrap();
+        while events.next().await.unwrap().target.unwrap().path != root.join("a") {}
+        assert_eq!(kanshi.watches()[0].directories, 1);
+
+        kanshi.close();
+        run.await.unwrap().unwrap();
+    }
 }
@@ -0,0 +1,52 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The names directories held when they were first looked at. Events don't say
+/// whether a rename replaced something, so this is how saving over a file is told
+/// apart from creating one.
+///
+/// A directory is read when an event in it is handled, not when the event happened,
+/// so a file already renamed into place by then looks like it was there before.
+#[derive(Default)]
+pub(crate) struct Listings(HashMap<PathBuf, HashSet<OsString>>);
+
+impl Listings {
+    /// Reads the directory `path` is in, unless it already was.
+    pub(crate) fn list_parent(&mut self, path: &Path) {
+        let Some(parent) = path.parent() else {
+            return;
+        };
+        if self.0.contains_key(parent) {
+            return;
+        }
+        let names = match fs::read_dir(parent) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name())
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
+        self.0.insert(parent.to_path_buf(), names);
+    }
+
+    /// Whether `path` was there when its directory was read.
+    pub(crate) fn listed(&self, path: &Path) -> bool {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return false;
+        };
+        self.0.get(parent).is_some_and(|names| names.contains(name))
+    }
+
+    /// Forgets the directories none of `paths` are in, so they are read again.
+    pub(crate) fn keep<'a>(&mut self, paths: impl Iterator<Item = &'a PathBuf> + Clone) {
+        self.0.retain(|directory, _| {
+            paths
+                .clone()
+                .any(|path| path.parent() == Some(directory.as_path()))
+        });
+    }
+}
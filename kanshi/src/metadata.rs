@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{FileSystemEventType, FileSystemTargetKind, MetadataChange};
+
+/// Remembers the last stat seen for paths that reported attribute changes, so
+/// the next change to the same path can say which attributes changed.
+///
+/// Paths are only stat'ed once they report an attribute change, so the first
+/// change to a path is reported without detail.
+#[derive(Default)]
+pub(crate) struct MetadataCache {
+    entries: HashMap<PathBuf, Snapshot>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Snapshot {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: (i64, i64),
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl From<&fs::Metadata> for Snapshot {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Snapshot {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            atime: (metadata.atime(), metadata.atime_nsec()),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+impl MetadataCache {
+    /// Fills in the attribute classes of a `Metadata` event and forgets deleted paths.
+    pub(crate) fn update(&mut self, event_type: &mut FileSystemEventType, path: &Path) {
+        match event_type {
+            FileSystemEventType::Metadata(changes) => *changes = self.changes(path),
+            FileSystemEventType::Delete => {
+                self.entries.remove(path);
+            }
+            _ => {}
+        }
+    }
+
+    /// Stats `path` and compares it with the previous stat, if there was one.
+    pub(crate) fn changes(&mut self, path: &Path) -> Option<MetadataChange> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            self.entries.remove(path);
+            return None;
+        };
+
+        let current = Snapshot::from(&metadata);
+        let previous = self.entries.insert(path.to_owned(), current)?;
+
+        let mut changes = MetadataChange::empty();
+        if previous.mode != current.mode {
+            changes |= MetadataChange::MODE;
+        }
+        if previous.uid != current.uid || previous.gid != current.gid {
+            changes |= MetadataChange::OWNER;
+        }
+        if previous.atime != current.atime || previous.mtime != current.mtime {
+            changes |= MetadataChange::TIMES;
+        }
+        // Setting an extended attribute only bumps the inode change time.
+        if changes.is_empty() && previous.ctime != current.ctime {
+            changes |= MetadataChange::XATTR;
+        }
+
+        if changes.is_empty() {
+            None
+        } else {
+            Some(changes)
+        }
+    }
+}
+
+impl From<fs::FileType> for FileSystemTargetKind {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_dir() {
+            FileSystemTargetKind::Directory
+        } else if file_type.is_file() {
+            FileSystemTargetKind::File
+        } else if file_type.is_symlink() {
+            FileSystemTargetKind::Symlink
+        } else if file_type.is_socket() {
+            FileSystemTargetKind::Socket
+        } else if file_type.is_fifo() {
+            FileSystemTargetKind::Fifo
+        } else if file_type.is_block_device() {
+            FileSystemTargetKind::BlockDevice
+        } else if file_type.is_char_device() {
+            FileSystemTargetKind::CharDevice
+        } else {
+            FileSystemTargetKind::Unknown
+        }
+    }
+}
+
+impl FileSystemTargetKind {
+    /// Event masks only say whether an item is a directory. Anything else is
+    /// looked up with `lstat`, which fails if the item is already gone.
+    pub(crate) fn resolve(self, path: &Path) -> FileSystemTargetKind {
+        if self == FileSystemTargetKind::Directory {
+            return self;
+        }
+
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.file_type().into(),
+            Err(_) => FileSystemTargetKind::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::MetadataCache;
+    use crate::{FileSystemTargetKind, MetadataChange};
+
+    #[test]
+    fn detects_mode_change() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("file");
+        fs::write(&path, b"").unwrap();
+
+        let mut cache = MetadataCache::default();
+        assert_eq!(cache.changes(&path), None);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(cache.changes(&path), Some(MetadataChange::MODE));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cache.changes(&path), None);
+    }
+
+    #[test]
+    fn resolves_symlinks_without_following_them() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("link");
+        std::os::unix::fs::symlink("/", &path).unwrap();
+
+        let kind = FileSystemTargetKind::File.resolve(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(kind, FileSystemTargetKind::Symlink);
+        assert_eq!(
+            FileSystemTargetKind::File.resolve(&path),
+            FileSystemTargetKind::Unknown
+        );
+    }
+}
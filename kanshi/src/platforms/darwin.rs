@@ -1,7 +1,11 @@
-use std::{borrow::Borrow, pin::Pin};
+use std::{borrow::Borrow, fmt, path::Path, pin::Pin, time::Duration};
 
-use crate::{KanshiError, KanshiImpl};
+use crate::{
+    atomic_saves::atomic_saves, BackpressurePolicy, EventMask, KanshiError, KanshiImpl,
+    RecursionMode, RootPaths, WatchHandle, DEFAULT_EVENT_BUFFER_CAPACITY,
+};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KanshiEngines {
     FSEvents,
     // KQueue,
@@ -18,11 +22,70 @@ impl KanshiEngines {
     }
 }
 
+impl fmt::Display for KanshiEngines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KanshiEngines::FSEvents => f.write_str("fsevents"),
+        }
+    }
+}
+
 mod core_foundation;
 mod fsevents;
 
 pub struct KanshiOptions {
     pub force_engine: Option<KanshiEngines>,
+    /// Number of events buffered for each subscriber of `get_events_stream()`.
+    pub event_buffer_capacity: usize,
+    /// What to do once a subscriber's buffer is full.
+    pub backpressure: BackpressurePolicy,
+    /// Report renames as a `MovedTo` event followed by a `MovedFrom` event instead
+    /// of a single `Rename` event. Kept for compatibility with older consumers.
+    pub split_renames: bool,
+    /// Event classes to subscribe to.
+    pub event_mask: EventMask,
+    /// How far below each watched directory to watch subdirectories.
+    pub recursion: RecursionMode,
+    /// Follow symlinked directories. Only supported on Linux, FSEvents reports
+    /// changes below a symlink's target under the target's own path.
+    pub follow_symlinks: bool,
+    /// Whether event paths start with the watch root as passed to `watch()` or
+    /// with its canonical path.
+    pub root_paths: RootPaths,
+    /// Globs a path below a watch root has to match for its events to be reported.
+    /// Everything is reported if it is empty.
+    pub include: Vec<String>,
+    /// Globs for paths below a watch root to ignore. Excluded directories are not
+    /// watched at all, and neither is anything below them.
+    pub exclude: Vec<String>,
+    /// Also exclude what `.gitignore`, `.ignore` and `.git/info/exclude` files ignore,
+    /// and `.git` directories themselves. The rules are read again when one of those
+    /// files changes.
+    pub respect_ignore_files: bool,
+    /// Report a file saved through a temporary file and a rename, as editors do, as a
+    /// single `Modify` of the file, or a `Create` if it wasn't in its directory when
+    /// the save started. New and renamed files are held back up to this long to tell.
+    /// `None` reports events as they come.
+    pub atomic_saves: Option<Duration>,
+}
+
+impl Default for KanshiOptions {
+    fn default() -> Self {
+        KanshiOptions {
+            force_engine: None,
+            event_buffer_capacity: DEFAULT_EVENT_BUFFER_CAPACITY,
+            backpressure: BackpressurePolicy::default(),
+            split_renames: false,
+            event_mask: EventMask::default(),
+            recursion: RecursionMode::default(),
+            follow_symlinks: false,
+            root_paths: RootPaths::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            respect_ignore_files: false,
+            atomic_saves: None,
+        }
+    }
 }
 
 pub use fsevents::FSEventsTracer;
@@ -35,6 +98,7 @@ enum Engines {
 #[derive(Clone)]
 pub struct Kanshi {
     engine: Engines,
+    atomic_saves: Option<Duration>,
 }
 
 impl KanshiImpl<KanshiOptions> for Kanshi {
@@ -42,8 +106,10 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
     where
         Self: Sized + Clone,
     {
+        let atomic_saves = opts.atomic_saves;
         Ok(Kanshi {
             engine: Engines::FSEvents(FSEventsTracer::new(opts)?),
+            atomic_saves,
         })
     }
 
@@ -53,12 +119,30 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
         }
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    async fn ready(&self) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.ready().await,
+        }
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
         match self.engine.borrow() {
             Engines::FSEvents(fsevents) => fsevents.watch(dir).await,
         }
     }
 
+    async fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.unwatch(dir).await,
+        }
+    }
+
+    fn watches(&self) -> Vec<WatchHandle> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.watches(),
+        }
+    }
+
     fn get_events_stream(
         &self,
     ) -> Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>> {
@@ -70,7 +154,18 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
             }
         };
 
-        events_stream
+        match self.atomic_saves {
+            Some(window) => atomic_saves(events_stream, window),
+            None => events_stream,
+        }
+    }
+
+    fn get_status_stream(
+        &self,
+    ) -> Pin<Box<dyn futures::Stream<Item = crate::KanshiStatus> + Send>> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.get_status_stream(),
+        }
     }
 
     fn close(&self) -> bool {
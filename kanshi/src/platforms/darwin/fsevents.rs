@@ -1,13 +1,14 @@
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::io;
 use std::os::raw::c_void;
 use std::path::{self, Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
 
 use async_stream::stream;
 use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Sender;
 use tokio::sync::{Mutex, RwLock};
 use tokio_util::sync::CancellationToken;
 
@@ -15,26 +16,38 @@ use super::core_foundation::types::{
     dispatch_queue_t, CFIndex, CFMutableArrayRef, FSEventStreamEventFlags, FSEventStreamRef,
 };
 use super::core_foundation::{self as CoreFoundation, types as CFTypes};
-use super::KanshiOptions;
+use super::{KanshiEngines, KanshiOptions};
+use crate::channel::EventSender;
+use crate::filter::PathFilter;
+use crate::metadata::MetadataCache;
 use crate::platforms::darwin::core_foundation::types::{
     kCFNumberSInt64Type, kFSEventStreamEventExtendedDataPathKey,
     kFSEventStreamEventExtendedFileIDKey,
 };
 use crate::platforms::darwin::core_foundation::{CFArrayGetValueAtIndex, CFDictionaryGetValue};
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
-    KanshiImpl,
+    FileSystemEvent, FileSystemEventType, FileSystemOperation, FileSystemTarget,
+    FileSystemTargetKind, KanshiError, KanshiImpl, KanshiStatus, MetadataChange, RecursionMode,
+    WatchHandle,
 };
 
 #[derive(Clone)]
 pub struct FSEventsTracer {
     stream: Arc<RwLock<Option<WrappedEventStreamRef>>>,
     dispatch_queue: Arc<RwLock<Option<WrappedDispatchQueue>>>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    context: Arc<CallbackContext>,
     cancellation_token: CancellationToken,
     paths_to_watch: Arc<Mutex<Vec<PathBuf>>>,
 }
 
+/// Passed to `callback` through `FSEventStreamContext->info`.
+struct CallbackContext {
+    sender: EventSender,
+    split_renames: bool,
+    recursion: RecursionMode,
+    metadata_cache: StdMutex<MetadataCache>,
+}
+
 pub struct WrappedEventStreamRef(FSEventStreamRef);
 unsafe impl Send for WrappedEventStreamRef {}
 unsafe impl Sync for WrappedEventStreamRef {}
@@ -43,6 +56,35 @@ pub struct WrappedDispatchQueue(dispatch_queue_t);
 unsafe impl Send for WrappedDispatchQueue {}
 unsafe impl Sync for WrappedDispatchQueue {}
 
+/// FSEvents reports ownership and extended attribute changes directly. Mode and
+/// time changes share `ItemInodeMetaMod`, so those come from the stat cache.
+fn metadata_changes(
+    flags: FSEventStreamEventFlags,
+    cached: Option<MetadataChange>,
+) -> Option<MetadataChange> {
+    let mut changes = MetadataChange::empty();
+    if flags.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemChangeOwner) {
+        changes |= MetadataChange::OWNER;
+    }
+    if flags.intersects(
+        FSEventStreamEventFlags::kFSEventStreamEventFlagItemXattrMod
+            | FSEventStreamEventFlags::kFSEventStreamEventFlagItemFinderInfoMod,
+    ) {
+        changes |= MetadataChange::XATTR;
+    }
+    if flags.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemInodeMetaMod) {
+        if let Some(cached) = cached {
+            changes |= cached & (MetadataChange::MODE | MetadataChange::TIMES);
+        }
+    }
+
+    if changes.is_empty() {
+        None
+    } else {
+        Some(changes)
+    }
+}
+
 extern "C" fn callback(
     _stream_ref: *const CFTypes::FSEventStreamRef, // ConstFSEventStreamRef - Reference to the stream this event originated from
     info: CFTypes::CFRef, // *mut FSEventStreamContext->info - Optionally supplied context during stream creation.
@@ -51,7 +93,8 @@ extern "C" fn callback(
     event_flags: *const CFTypes::FSEventStreamEventFlags, // eventFlags - Array of EventFlags corresponding to each event
     _event_ids: *const CFTypes::FSEventStreamId, // eventIds - Array of EventIds corresponding to each event. This Id is guaranteed to always be increasing.
 ) {
-    let sender = info as *const Sender<FileSystemEvent>;
+    let context = unsafe { &*(info as *const CallbackContext) };
+    let timestamp = SystemTime::now();
     let mut inode_map = HashMap::<i64, FileSystemEvent>::new();
     for idx in 0..num_event {
         let dict = unsafe { CFArrayGetValueAtIndex(event_paths, idx as CFIndex) };
@@ -78,13 +121,40 @@ extern "C" fn callback(
 
         let flag = unsafe { *event_flags.add(idx) };
 
+        // FSEvents coalesced or dropped events below `path`, so it has to be rescanned.
+        if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagMustScanSubDirs) {
+            let event = FileSystemEvent::overflow(None, Some(PathBuf::from(path)), timestamp);
+            if let Err(e) = context.sender.send_blocking(event) {
+                context.sender.warn(e);
+            }
+            continue;
+        }
+
+        // The stream always covers the whole tree, so entries of directories beyond
+        // the recursion limit are dropped here.
+        let depth = context.sender.watches().depth(Path::new(&path));
+        if depth.is_some_and(|depth| depth > 0 && !context.recursion.includes(depth - 1)) {
+            continue;
+        }
+
         let kind = if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemIsDir) {
             FileSystemTargetKind::Directory
+        } else if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemIsSymlink) {
+            FileSystemTargetKind::Symlink
         } else {
-            FileSystemTargetKind::File
+            // FSEvents also flags sockets, FIFOs and devices as files.
+            match FileSystemTargetKind::File.resolve(Path::new(&path)) {
+                FileSystemTargetKind::Unknown
+                    if flag
+                        .contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemIsFile) =>
+                {
+                    FileSystemTargetKind::File
+                }
+                kind => kind,
+            }
         };
 
-        let mut event_type = match flag {
+        let event_type = match flag {
             x if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemCreated) => {
                 if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemRemoved) {
                     FileSystemEventType::Delete
@@ -95,7 +165,10 @@ extern "C" fn callback(
                 }
             }
             x if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemRemoved) => {
-                FileSystemEventType::Delete
+                let mut metadata_cache = context.metadata_cache.lock().unwrap();
+                let mut event_type = FileSystemEventType::Delete;
+                metadata_cache.update(&mut event_type, Path::new(&path));
+                event_type
             }
             x if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemModified) => {
                 FileSystemEventType::Modify
@@ -103,101 +176,150 @@ extern "C" fn callback(
             x if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemRenamed) => {
                 FileSystemEventType::Move
             }
-            x => {
-                eprintln!("Unknown Mask Received - {:?}", x);
-                FileSystemEventType::Unknown
+            x if x.intersects(
+                FSEventStreamEventFlags::kFSEventStreamEventFlagItemInodeMetaMod
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemChangeOwner
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemXattrMod
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemFinderInfoMod,
+            ) =>
+            {
+                let mut metadata_cache = context.metadata_cache.lock().unwrap();
+                FileSystemEventType::Metadata(metadata_changes(
+                    x,
+                    metadata_cache.changes(Path::new(&path)),
+                ))
             }
+            _ => FileSystemEventType::Unknown,
         };
 
         if event_type == FileSystemEventType::Move && inode.is_some() {
             let inode = inode.unwrap();
             if inode_map.contains_key(&inode) {
-                let mut old_event = inode_map.remove(&inode).unwrap();
-                old_event.event_type = FileSystemEventType::MovedTo(OsString::from(path.clone()));
-                event_type =
-                    FileSystemEventType::MovedFrom(old_event.target.as_ref().unwrap().path.clone());
-
-                let event = FileSystemEvent {
-                    event_type,
-                    target: Some(FileSystemTarget {
-                        kind,
-                        path: OsString::from(path),
-                    }),
-                };
-
-                if let Err(e) = unsafe { (*sender).send(old_event) } {
-                    eprintln!("Send Error Occurred - {:?}", e.to_string());
-                }
+                let old_event = inode_map.remove(&inode).unwrap();
+                let events = FileSystemEvent::rename(
+                    old_event.target.unwrap().path,
+                    PathBuf::from(path),
+                    kind,
+                    timestamp,
+                    context.split_renames,
+                );
 
-                if let Err(e) = unsafe { (*sender).send(event) } {
-                    eprintln!("Send Error Occurred - {:?}", e.to_string());
+                for event in events {
+                    if let Err(e) = context.sender.send_blocking(event) {
+                        context.sender.warn(e);
+                    }
                 }
             } else {
                 // event_type =
-                let event = FileSystemEvent {
+                let event = FileSystemEvent::new(
                     event_type,
-                    target: Some(FileSystemTarget {
-                        kind,
-                        path: OsString::from(path),
-                    }),
-                };
+                    Some(FileSystemTarget::new(path, kind)),
+                    timestamp,
+                );
 
                 inode_map.insert(inode, event);
             }
         } else {
-            let event = FileSystemEvent {
+            let event = FileSystemEvent::new(
                 event_type,
-                target: Some(FileSystemTarget {
-                    kind,
-                    path: OsString::from(path),
-                }),
-            };
+                Some(FileSystemTarget::new(path, kind)),
+                timestamp,
+            );
 
-            if let Err(e) = unsafe { (*sender).send(event) } {
-                eprintln!("Send Error Occurred - {:?}", e.to_string());
+            if let Err(e) = context.sender.send_blocking(event) {
+                context.sender.warn(e);
             }
         }
     }
 }
 
 impl KanshiImpl<KanshiOptions> for FSEventsTracer {
-    fn new(_opts: KanshiOptions) -> Result<FSEventsTracer, KanshiError> {
-        let (tx, _rx) = tokio::sync::broadcast::channel(32);
+    fn new(opts: KanshiOptions) -> Result<FSEventsTracer, KanshiError> {
+        let tx = EventSender::new(
+            opts.event_buffer_capacity,
+            opts.backpressure,
+            opts.event_mask,
+            opts.root_paths,
+            PathFilter::new(&opts.include, &opts.exclude, opts.respect_ignore_files)?,
+        )?;
 
         Ok(FSEventsTracer {
             stream: Arc::new(RwLock::new(None)),
-            sender: tx,
+            context: Arc::new(CallbackContext {
+                sender: tx,
+                split_renames: opts.split_renames,
+                recursion: opts.recursion,
+                metadata_cache: StdMutex::new(MetadataCache::default()),
+            }),
             cancellation_token: CancellationToken::new(),
             paths_to_watch: Arc::new(Mutex::new(Vec::new())),
             dispatch_queue: Arc::new(RwLock::new(None)),
         })
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    async fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
         if let Some(_) = *self.stream.read().await {
             return Err(KanshiError::ListenerStartedError);
         }
 
+        let dir = dir.as_ref();
         let mut paths_to_watch = self.paths_to_watch.lock().await;
-        let path = path::absolute(Path::new(dir));
+        let path = path::absolute(dir);
         if let Ok(path) = path {
             if !path.exists() {
-                Err(KanshiError::FileSystemError(
-                    "ENOENT Directory does not exist".to_owned(),
+                Err(KanshiError::file_system(
+                    FileSystemOperation::Mark,
+                    Some(&path),
+                    io::Error::from_raw_os_error(libc::ENOENT),
                 ))
             } else {
-                paths_to_watch.push(path);
-                Ok(())
+                paths_to_watch.push(path.clone());
+                // The stream covers the whole tree, there are no per-directory marks.
+                Ok(self
+                    .context
+                    .sender
+                    .watches()
+                    .add(path, 1, KanshiEngines::FSEvents))
             }
         } else {
-            Err(KanshiError::FileSystemError(
-                path.err().unwrap().to_string(),
+            Err(KanshiError::file_system(
+                FileSystemOperation::Traverse,
+                Some(dir),
+                path.err().unwrap(),
+            ))
+        }
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        if let Some(_) = *self.stream.read().await {
+            return Err(KanshiError::ListenerStartedError);
+        }
+
+        let dir = dir.as_ref();
+        let mut paths_to_watch = self.paths_to_watch.lock().await;
+        let path = path::absolute(dir)
+            .map_err(|e| KanshiError::file_system(FileSystemOperation::Traverse, Some(dir), e))?;
+
+        let watched = paths_to_watch.len();
+        paths_to_watch.retain(|watched_path| *watched_path != path);
+        if paths_to_watch.len() == watched {
+            Err(KanshiError::file_system(
+                FileSystemOperation::Unmark,
+                Some(&path),
+                io::Error::from_raw_os_error(libc::ENOENT),
             ))
+        } else {
+            self.context.sender.watches().remove(&path);
+            Ok(())
         }
     }
 
+    fn watches(&self) -> Vec<WatchHandle> {
+        self.context.sender.watches().list()
+    }
+
     fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
-        let mut listener = self.sender.subscribe();
+        let mut listener = self.context.sender.subscribe();
         let cancel_token = self.cancellation_token.clone();
 
         Box::pin(stream! {
@@ -214,7 +336,7 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
                             Err(e) => {
                               match e {
                                 RecvError::Closed => break 'outer,
-                                _ => ()
+                                RecvError::Lagged(lost) => yield listener.lagged(lost),
                             }}
                         }
                     }
@@ -225,13 +347,75 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
 
     async fn start(&self) -> Result<(), KanshiError> {
         if let Some(_) = *self.stream.read().await {
+            // The running listener isn't affected, so this is only a warning there.
+            self.context.sender.warn(KanshiError::ListenerStartedError);
             return Err(KanshiError::ListenerStartedError);
         }
 
+        self.context.sender.run(self.run_stream()).await
+    }
+
+    async fn ready(&self) -> Result<(), KanshiError> {
+        self.context.sender.ready().await
+    }
+
+    fn get_status_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStatus> + Send>> {
+        self.context.sender.status_stream()
+    }
+
+    fn close(&self) -> bool {
+        if self.cancellation_token.is_cancelled() {
+            return true;
+        }
+
+        self.cancellation_token.cancel();
+        self.context.sender.stopped(None);
+
+        let mut has_errored = false;
+
+        let stream_ref = self.stream.try_read();
+        if let Ok(stream) = stream_ref {
+            if stream.is_some() {
+                let stream = stream.as_ref().unwrap();
+                unsafe {
+                    CoreFoundation::FSEventStreamStop(stream.0);
+                    CoreFoundation::FSEventStreamInvalidate(stream.0);
+                    CoreFoundation::FSEventStreamRelease(stream.0);
+                };
+            }
+        } else {
+            let e = io::Error::other(stream_ref.err().unwrap().to_string());
+            self.context
+                .sender
+                .warn(KanshiError::file_system(FileSystemOperation::Other, None, e));
+            has_errored = true;
+        }
+
+        let dq_ref = self.dispatch_queue.try_read();
+        if let Ok(dq) = dq_ref {
+            if dq.is_some() {
+                let dq = dq.as_ref().unwrap();
+                unsafe {
+                    CoreFoundation::dispatch_release(dq.0);
+                };
+            }
+        } else {
+            let e = io::Error::other(dq_ref.err().unwrap().to_string());
+            self.context
+                .sender
+                .warn(KanshiError::file_system(FileSystemOperation::Other, None, e));
+            has_errored = true;
+        }
+
+        !has_errored
+    }
+}
+
+impl FSEventsTracer {
+    async fn run_stream(&self) -> Result<(), KanshiError> {
         {
             let paths_to_watch = self.paths_to_watch.lock().await;
-            // let sender = self.sender.clone();
-            let ptr: *const Sender<FileSystemEvent> = &self.sender;
+            let ptr: *const CallbackContext = Arc::as_ptr(&self.context);
 
             let context = CFTypes::FSEventStreamContext {
                 version: 0 as *mut i64,
@@ -252,22 +436,33 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
 
                 for path in paths_to_watch.iter() {
                     if !path.exists() {
-                        return Err(KanshiError::FileSystemError(format!(
-                            "{:?} does not exist",
-                            path
-                        )));
+                        return Err(KanshiError::file_system(
+                            FileSystemOperation::Start,
+                            Some(path),
+                            io::Error::from_raw_os_error(libc::ENOENT),
+                        ));
                     }
 
-                    let canon_path = path.canonicalize()?;
-                    let path_as_str = canon_path.to_str().unwrap();
+                    let canon_path = path.canonicalize().map_err(|e| {
+                        KanshiError::file_system(FileSystemOperation::Start, Some(path), e)
+                    })?;
+                    // FSEvents takes paths as CFStrings, which have to be valid UTF-8.
+                    let Some(path_as_str) = canon_path.to_str() else {
+                        return Err(KanshiError::file_system(
+                            FileSystemOperation::Start,
+                            Some(path),
+                            io::Error::from(io::ErrorKind::InvalidInput),
+                        ));
+                    };
                     let err: CFTypes::CFErrorRef = std::ptr::null_mut();
                     let cf_path = CoreFoundation::rust_str_to_cf_string(path_as_str, err);
                     if cf_path.is_null() {
                         CoreFoundation::CFRelease(err as CFTypes::CFRef);
-                        return Err(KanshiError::FileSystemError(format!(
-                            "{:?} does not exist",
-                            path
-                        )));
+                        return Err(KanshiError::file_system(
+                            FileSystemOperation::Start,
+                            Some(path),
+                            io::Error::from(io::ErrorKind::InvalidInput),
+                        ));
                     } else {
                         CoreFoundation::CFArrayAppendValue(paths, cf_path);
                         CoreFoundation::CFRelease(cf_path);
@@ -317,6 +512,8 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
             if let Ok(mut dq_ref) = self.dispatch_queue.try_write() {
                 *dq_ref = Some(WrappedDispatchQueue(dispatch_queue));
             }
+
+            self.context.sender.listening();
         }
 
         self.cancellation_token.cancelled().await;
@@ -326,46 +523,4 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
 
         Ok(())
     }
-
-    fn close(&self) -> bool {
-        if self.cancellation_token.is_cancelled() {
-            return true;
-        }
-
-        self.cancellation_token.cancel();
-
-        let mut has_errored = false;
-
-        let stream_ref = self.stream.try_read();
-        if let Ok(stream) = stream_ref {
-            if stream.is_some() {
-                let stream = stream.as_ref().unwrap();
-                unsafe {
-                    CoreFoundation::FSEventStreamStop(stream.0);
-                    CoreFoundation::FSEventStreamInvalidate(stream.0);
-                    CoreFoundation::FSEventStreamRelease(stream.0);
-                };
-            }
-        } else {
-            let e = stream_ref.err().unwrap();
-            eprintln!("error occurred releasing stream {e}");
-            has_errored = true;
-        }
-
-        let dq_ref = self.dispatch_queue.try_read();
-        if let Ok(dq) = dq_ref {
-            if dq.is_some() {
-                let dq = dq.as_ref().unwrap();
-                unsafe {
-                    CoreFoundation::dispatch_release(dq.0);
-                };
-            }
-        } else {
-            let e = dq_ref.err().unwrap();
-            eprintln!("error occurred releasing stream {e}");
-            has_errored = true;
-        }
-
-        !has_errored
-    }
 }
@@ -1,8 +1,11 @@
-use std::{borrow::Borrow, pin::Pin};
+use std::{borrow::Borrow, fmt, path::Path, pin::Pin, time::Duration};
 
-use crate::{KanshiError, KanshiImpl};
+use crate::{
+    atomic_saves::atomic_saves, BackpressurePolicy, EventMask, KanshiError, KanshiImpl,
+    RecursionMode, RootPaths, WatchHandle, DEFAULT_EVENT_BUFFER_CAPACITY,
+};
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KanshiEngines {
     Fanotify,
     Inotify,
@@ -20,8 +23,21 @@ impl KanshiEngines {
     }
 }
 
+impl fmt::Display for KanshiEngines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KanshiEngines::Fanotify => f.write_str("fanotify"),
+            KanshiEngines::Inotify => f.write_str("inotify"),
+        }
+    }
+}
+
+pub mod blocking;
 mod fanotify;
+mod files;
 mod inotify;
+mod links;
+mod reader;
 
 use async_stream::stream;
 pub use fanotify::*;
@@ -29,6 +45,57 @@ pub use inotify::*;
 
 pub struct KanshiOptions {
     pub force_engine: Option<KanshiEngines>,
+    /// Number of events buffered for each subscriber of `get_events_stream()`.
+    pub event_buffer_capacity: usize,
+    /// What to do once a subscriber's buffer is full.
+    pub backpressure: BackpressurePolicy,
+    /// Report renames as a `MovedTo` event followed by a `MovedFrom` event instead
+    /// of a single `Rename` event. Kept for compatibility with older consumers.
+    pub split_renames: bool,
+    /// Event classes to subscribe to.
+    pub event_mask: EventMask,
+    /// How far below each watched directory to watch subdirectories.
+    pub recursion: RecursionMode,
+    /// Watch the directories symlinks below a watched directory point to, reporting
+    /// their events under the symlink's path.
+    pub follow_symlinks: bool,
+    /// Whether event paths start with the watch root as passed to `watch()` or
+    /// with its canonical path.
+    pub root_paths: RootPaths,
+    /// Globs a path below a watch root has to match for its events to be reported.
+    /// Everything is reported if it is empty.
+    pub include: Vec<String>,
+    /// Globs for paths below a watch root to ignore. Excluded directories are not
+    /// watched at all, and neither is anything below them.
+    pub exclude: Vec<String>,
+    /// Also exclude what `.gitignore`, `.ignore` and `.git/info/exclude` files ignore,
+    /// and `.git` directories themselves. The rules are read again when one of those
+    /// files changes.
+    pub respect_ignore_files: bool,
+    /// Report a file saved through a temporary file and a rename, as editors do, as a
+    /// single `Modify` of the file, or a `Create` if it wasn't in its directory when
+    /// the save started. New and renamed files are held back up to this long to tell.
+    /// `None` reports events as they come. Not applied by `blocking::Watcher`.
+    pub atomic_saves: Option<Duration>,
+}
+
+impl Default for KanshiOptions {
+    fn default() -> Self {
+        KanshiOptions {
+            force_engine: None,
+            event_buffer_capacity: DEFAULT_EVENT_BUFFER_CAPACITY,
+            backpressure: BackpressurePolicy::default(),
+            split_renames: false,
+            event_mask: EventMask::default(),
+            recursion: RecursionMode::default(),
+            follow_symlinks: false,
+            root_paths: RootPaths::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            respect_ignore_files: false,
+            atomic_saves: None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -40,6 +107,7 @@ enum Engines {
 #[derive(Clone)]
 pub struct Kanshi {
     engine: Engines,
+    atomic_saves: Option<Duration>,
 }
 
 impl KanshiImpl<KanshiOptions> for Kanshi {
@@ -47,8 +115,8 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
     where
         Self: Sized + Clone,
     {
-        let chosen_engine: KanshiEngines = if let Some(engine) = opts.force_engine.as_ref() {
-            engine.clone()
+        let chosen_engine: KanshiEngines = if let Some(engine) = opts.force_engine {
+            engine
         } else {
             let uid = unsafe { libc::geteuid() };
 
@@ -59,11 +127,13 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
             }
         };
 
+        let atomic_saves = opts.atomic_saves;
         Ok(Kanshi {
             engine: match chosen_engine {
                 KanshiEngines::Inotify => Engines::INotify(INotifyTracer::new(opts)?),
                 KanshiEngines::Fanotify => Engines::Fanotify(FanotifyTracer::new(opts)?),
             },
+            atomic_saves,
         })
     }
 
@@ -74,13 +144,34 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
         }
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    async fn ready(&self) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.ready().await,
+            Engines::INotify(notify) => notify.ready().await,
+        }
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
         match self.engine.borrow() {
             Engines::Fanotify(fan) => fan.watch(dir).await,
             Engines::INotify(notify) => notify.watch(dir).await,
         }
     }
 
+    async fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.unwatch(dir).await,
+            Engines::INotify(notify) => notify.unwatch(dir).await,
+        }
+    }
+
+    fn watches(&self) -> Vec<WatchHandle> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.watches(),
+            Engines::INotify(notify) => notify.watches(),
+        }
+    }
+
     fn get_events_stream(
         &self,
     ) -> Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>> {
@@ -101,6 +192,11 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
 
         // let events_stream = *events_stream;
 
+        let events_stream = match self.atomic_saves {
+            Some(window) => atomic_saves(events_stream, window),
+            None => events_stream,
+        };
+
         Box::pin(stream! {
           for await item in events_stream {
             yield item
@@ -108,6 +204,15 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
         })
     }
 
+    fn get_status_stream(
+        &self,
+    ) -> Pin<Box<dyn futures::Stream<Item = crate::KanshiStatus> + Send>> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.get_status_stream(),
+            Engines::INotify(notify) => notify.get_status_stream(),
+        }
+    }
+
     fn close(&self) -> bool {
         match self.engine.borrow() {
             Engines::Fanotify(fan) => fan.close(),
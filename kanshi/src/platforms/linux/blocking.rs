@@ -0,0 +1,236 @@
+//! A synchronous API for programs that don't run an async runtime of their own.
+//!
+//! A `Watcher` reads the inotify or fanotify fd on the calling thread, and only while
+//! it is waiting for an event, so it never starts or needs a runtime. Kanshi still
+//! depends on Tokio to build, though. Events that happen in between reads are queued
+//! by the kernel, so `event_buffer_capacity` and `backpressure` don't apply.
+
+use std::{
+    os::fd::{AsFd, OwnedFd},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use thiserror::Error;
+
+use crate::{
+    channel::EventReceiver, metadata::MetadataCache, BackpressurePolicy, FileSystemEvent,
+    KanshiError, KanshiImpl, WatchHandle,
+};
+
+use super::{
+    inotify::ReaderState,
+    reader::{read_blocking, Reader},
+    Engines, FanotifyTracer, INotifyTracer, Kanshi, KanshiOptions,
+};
+
+#[derive(Error, Debug, Clone)]
+pub enum RecvTimeoutError {
+    #[error("no event arrived before the timeout")]
+    Timeout,
+
+    #[error(transparent)]
+    Kanshi(#[from] KanshiError),
+}
+
+/// Watches directories and hands out their events to a synchronous caller.
+pub struct Watcher {
+    kanshi: Kanshi,
+    reading: Mutex<Reading>,
+    // Written to by `close()`, to wake a thread waiting for events.
+    wake_rx: OwnedFd,
+    wake_tx: OwnedFd,
+    closed: AtomicBool,
+}
+
+struct Reading {
+    reader: EngineReader,
+    events: EventReceiver,
+}
+
+enum EngineReader {
+    Fanotify(FanotifyTracer, MetadataCache),
+    INotify(INotifyTracer, ReaderState),
+}
+
+impl Watcher {
+    /// Creates a watcher. The `backpressure` of `opts` is ignored and always
+    /// `Unbounded`: events are only read once the last batch was taken, so one batch
+    /// is all there ever is to buffer.
+    pub fn new(opts: KanshiOptions) -> Result<Watcher, KanshiError> {
+        let kanshi = Kanshi::new(KanshiOptions {
+            backpressure: BackpressurePolicy::Unbounded,
+            ..opts
+        })?;
+        let reader = match &kanshi.engine {
+            Engines::Fanotify(fan) => EngineReader::Fanotify(fan.clone(), Default::default()),
+            Engines::INotify(notify) => EngineReader::INotify(notify.clone(), Default::default()),
+        };
+        let events = match &reader {
+            EngineReader::Fanotify(fan, _) => fan.sender().subscribe(),
+            EngineReader::INotify(notify, _) => notify.sender().subscribe(),
+        };
+        let (wake_rx, wake_tx) = nix::unistd::pipe()?;
+
+        Ok(Watcher {
+            kanshi,
+            reading: Mutex::new(Reading { reader, events }),
+            wake_rx,
+            wake_tx,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Watches a new directory, or a single file.
+    pub fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
+        futures::executor::block_on(self.kanshi.watch(dir))
+    }
+
+    /// Stops watching a directory and everything below it.
+    pub fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        futures::executor::block_on(self.kanshi.unwatch(dir))
+    }
+
+    /// Lists the directory trees currently being watched.
+    pub fn watches(&self) -> Vec<WatchHandle> {
+        self.kanshi.watches()
+    }
+
+    /// Iterates over events as they happen. It ends once the watcher is closed or
+    /// fails. Use `recv()` to find out why.
+    pub fn events(&self) -> Events<'_> {
+        Events { watcher: self }
+    }
+
+    /// Waits for the next event.
+    pub fn recv(&self) -> Result<FileSystemEvent, KanshiError> {
+        loop {
+            match self.recv_deadline(None) {
+                Ok(event) => return Ok(event),
+                Err(RecvTimeoutError::Kanshi(e)) => return Err(e),
+                // Without a deadline there is nothing to time out, so it waits again.
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next event.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<FileSystemEvent, RecvTimeoutError> {
+        self.recv_deadline(Instant::now().checked_add(timeout))
+    }
+
+    /// Stops the watcher. Threads waiting for an event return `StreamClosedError`.
+    /// Returns false if it was already closed.
+    pub fn close(&self) -> bool {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.kanshi.close();
+        let _ = nix::unistd::write(&self.wake_tx, &[0]);
+        true
+    }
+
+    fn recv_deadline(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<FileSystemEvent, RecvTimeoutError> {
+        let mut reading = self.reading.lock().unwrap();
+        let mut polled = false;
+        loop {
+            match reading.events.recv().now_or_never() {
+                Some(Ok(event)) => return Ok(event),
+                Some(Err(_)) => return Err(KanshiError::StreamClosedError.into()),
+                None => {}
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(KanshiError::StreamClosedError.into());
+            }
+
+            // Even a zero timeout gets to read what is already queued.
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if polled && timeout == Some(Duration::ZERO) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            polled = true;
+
+            let wake = self.wake_rx.as_fd();
+            match &mut reading.reader {
+                EngineReader::Fanotify(fan, state) => read_blocking(fan, state, timeout, wake)?,
+                EngineReader::INotify(notify, state) => {
+                    read_blocking(notify, state, timeout, wake)?
+                }
+            }
+        }
+    }
+}
+
+/// Events from `Watcher::events()`.
+pub struct Events<'a> {
+    watcher: &'a Watcher,
+}
+
+impl Iterator for Events<'_> {
+    type Item = FileSystemEvent;
+
+    fn next(&mut self) -> Option<FileSystemEvent> {
+        self.watcher.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    use crate::{FileSystemEventType, KanshiEngines, KanshiOptions};
+
+    use super::{RecvTimeoutError, Watcher};
+
+    #[test]
+    fn reads_events_without_a_runtime() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("root");
+        fs::create_dir_all(&root).unwrap();
+
+        let watcher = Watcher::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..Default::default()
+        })
+        .unwrap();
+        watcher.watch(&root).unwrap();
+
+        assert!(matches!(
+            watcher.recv_timeout(Duration::ZERO),
+            Err(RecvTimeoutError::Timeout)
+        ));
+
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let event = watcher.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event.event_type, FileSystemEventType::Create));
+
+        // The move out of the tree has no other half, so it is reported once the
+        // pairing window passes.
+        let outside = temp.path().join("a.txt");
+        fs::rename(root.join("a.txt"), &outside).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let moved = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let event = watcher.recv_timeout(timeout).unwrap();
+            if matches!(event.event_type, FileSystemEventType::Move) {
+                break event;
+            }
+        };
+        assert!(moved.target.unwrap().path.ends_with("a.txt"));
+
+        assert!(watcher.close());
+        assert!(watcher.events().next().is_none());
+    }
+}
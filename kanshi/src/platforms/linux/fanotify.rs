@@ -1,35 +1,53 @@
 use std::{
-    collections::{HashSet, VecDeque}, ffi::{OsStr, OsString}, fs, io, os::{
-        fd::{AsFd, AsRawFd},
-        unix::fs::MetadataExt,
-    }, path::{Path, PathBuf}, pin::Pin, sync::Arc
+    collections::VecDeque,
+    ffi::OsStr,
+    fs, io,
+    os::fd::{AsFd, BorrowedFd},
+    path::{self, Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::SystemTime,
 };
 
 use async_stream::stream;
 use nix::{
     errno::Errno,
     fcntl::AT_FDCWD,
-    sys::{
-        epoll::Epoll,
-        fanotify::{Fanotify, FanotifyFidEventInfoType, FanotifyFidRecord, FanotifyInfoRecord},
+    sys::fanotify::{
+        Fanotify, FanotifyEvent, FanotifyFidEventInfoType, FanotifyFidRecord, FanotifyInfoRecord,
+        MaskFlags,
     },
 };
 use tokio::sync::broadcast::error::RecvError;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
-    KanshiImpl,
+    channel::EventSender,
+    filter::PathFilter,
+    metadata::MetadataCache,
+    EventMask, FileSystemEvent, FileSystemEventType, FileSystemOperation, FileSystemTarget,
+    FileSystemTargetKind, KanshiError, KanshiImpl, KanshiStatus, MoveDirection, RecursionMode,
+    WatchHandle,
 };
 
-use super::KanshiOptions;
+use super::{
+    files::FileWatches,
+    links::{ancestor_ids, dir_id, traversed_dir, FollowedLinks},
+    reader::{read_loop, Reader},
+    KanshiEngines, KanshiOptions,
+};
 
 #[derive(Clone)]
 pub struct FanotifyTracer {
     fanotify: Arc<Fanotify>,
-    epoll: Arc<Epoll>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    sender: EventSender,
     cancellation_token: CancellationToken,
+    split_renames: bool,
+    event_mask: EventMask,
+    recursion: RecursionMode,
+    follow_symlinks: bool,
+    files: FileWatches,
+    links: FollowedLinks,
 }
 
 #[repr(C)]
@@ -41,12 +59,13 @@ pub struct FileHandle {
 }
 
 impl KanshiImpl<KanshiOptions> for FanotifyTracer {
-    fn new(_opts: KanshiOptions) -> Result<FanotifyTracer, KanshiError> {
-        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
+    fn new(opts: KanshiOptions) -> Result<FanotifyTracer, KanshiError> {
         use nix::sys::fanotify::{EventFFlags, InitFlags};
 
+        // Non-blocking, so the reader can wait for it on the runtime's reactor.
         #[allow(non_snake_case)]
         let INIT_FLAGS: InitFlags = InitFlags::FAN_CLASS_NOTIF
+            | InitFlags::FAN_NONBLOCK
             | InitFlags::FAN_REPORT_DFID_NAME
             | InitFlags::FAN_UNLIMITED_QUEUE
             // | InitFlags::FAN_REPORT_TARGET_FID
@@ -59,84 +78,83 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
         let fanotify_fd = Fanotify::init(INIT_FLAGS, EVENT_FLAGS);
 
         if let Ok(fanotify) = fanotify_fd {
-            // Setup epoll
-            let epoll_event =
-                EpollEvent::new(EpollFlags::EPOLLIN, fanotify.as_fd().as_raw_fd() as u64);
-
-            let epoll_fd = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC);
-
-            if let Ok(epoll) = epoll_fd {
-                if let Err(e) = epoll.add(fanotify.as_fd(), epoll_event) {
-                    Err(KanshiError::FileSystemError(e.to_string()))
-                } else {
-                    let (tx, _rx) = tokio::sync::broadcast::channel(32);
-                    let engine = FanotifyTracer {
-                        // mark_set: HashSet::new(),
-                        fanotify: Arc::new(fanotify),
-                        epoll: Arc::new(epoll),
-                        sender: tx,
-                        // reciever: rx,
-                        cancellation_token: CancellationToken::new(),
-                    };
-                    Ok(engine)
-                }
-            } else {
-                let e = epoll_fd.err().unwrap();
-                Err(KanshiError::FileSystemError(e.to_string()))
-            }
+            let tx = EventSender::new(
+                opts.event_buffer_capacity,
+                opts.backpressure,
+                opts.event_mask,
+                opts.root_paths,
+                PathFilter::new(&opts.include, &opts.exclude, opts.respect_ignore_files)?,
+            )?;
+            let engine = FanotifyTracer {
+                // mark_set: HashSet::new(),
+                fanotify: Arc::new(fanotify),
+                sender: tx,
+                // reciever: rx,
+                cancellation_token: CancellationToken::new(),
+                split_renames: opts.split_renames,
+                event_mask: opts.event_mask,
+                recursion: opts.recursion,
+                follow_symlinks: opts.follow_symlinks,
+                files: FileWatches::default(),
+                links: FollowedLinks::default(),
+            };
+            Ok(engine)
         } else {
-            Err(KanshiError::FileSystemError(
-                io::Error::last_os_error().to_string(),
-            ))
+            let e = fanotify_fd.err().unwrap();
+            Err(KanshiError::file_system(FileSystemOperation::Init, None, e))
         }
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    async fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
         if self.cancellation_token.is_cancelled() {
             return Err(KanshiError::StreamClosedError);
         }
 
-        let mark_top_dir = mark(&self.fanotify, Path::new(dir));
+        let dir = dir.as_ref();
+        let absolute_path = path::absolute(dir).map_err(|e| {
+            KanshiError::file_system(FileSystemOperation::Traverse, Some(dir), e)
+        })?;
+        if absolute_path.is_file() {
+            return self.watch_file(absolute_path);
+        }
 
-        if let Ok(_) = mark_top_dir {
-            let mut traversal_queue = VecDeque::from([PathBuf::from(dir)]);
-            let mut visited = HashSet::<u64>::new();
+        let directories = self.mark_tree(&absolute_path, 0)?;
+        Ok(self
+            .sender
+            .watches()
+            .add(absolute_path, directories, KanshiEngines::Fanotify))
+    }
 
-            'outer: loop {
-                if let Some(next_dir) = traversal_queue.pop_front() {
-                    if let Ok(dir_items) = fs::read_dir(next_dir) {
-                        for dir_item in dir_items {
-                            if let Ok(dir_item_unwrapped) = dir_item {
-                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
-                                    let inode_number = metadata.ino();
-                                    if !visited.contains(&inode_number) && !metadata.is_symlink() {
-                                        visited.insert(inode_number);
-                                        if dir_item_unwrapped.path().is_dir() {
-                                            if let Err(e) =
-                                                mark(&self.fanotify, &dir_item_unwrapped.path())
-                                            {
-                                                return Err(e);
-                                            }
-                                            traversal_queue.push_back(dir_item_unwrapped.path());
-                                        }
-                                    }
-                                }
-                            } else {
-                                break 'outer;
-                            }
-                        }
-                    } else {
-                        break 'outer;
-                    }
-                } else {
-                    break 'outer;
-                }
+    async fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosedError);
+        }
+
+        let dir = dir.as_ref();
+        let absolute_path = path::absolute(dir).map_err(|e| {
+            KanshiError::file_system(FileSystemOperation::Traverse, Some(dir), e)
+        })?;
+        if let Some(file) = canonical_file(&absolute_path) {
+            if self.files.contains(&file) {
+                return self.unwatch_file(&absolute_path, &file);
             }
+        }
 
-            Ok(())
-        } else {
-            mark_top_dir
+        let watches = self.sender.watches();
+        if !watches.remove(&absolute_path) {
+            return Err(KanshiError::file_system(
+                FileSystemOperation::Unmark,
+                Some(&absolute_path),
+                Errno::ENOENT,
+            ));
         }
+        self.unmark_tree(&absolute_path, 0)?;
+        self.links.remove_unwatched(&absolute_path, watches);
+        Ok(())
+    }
+
+    fn watches(&self) -> Vec<WatchHandle> {
+        self.sender.watches().list()
     }
 
     fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
@@ -154,7 +172,7 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
                             Ok(x) => yield x,
                             Err(e) => match e {
                                 RecvError::Closed => break,
-                                _ => ()
+                                RecvError::Lagged(lost) => yield listener.lagged(lost),
                             }
                         }
                     }
@@ -166,190 +184,566 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
     }
 
     async fn start(&self) -> Result<(), KanshiError> {
-        use nix::sys::epoll::EpollEvent;
+        self.sender.run(self.read_events_loop()).await
+    }
 
-        let cancel_token = self.cancellation_token.clone();
-        let sender = self.sender.clone();
+    async fn ready(&self) -> Result<(), KanshiError> {
+        self.sender.ready().await
+    }
 
-        let mut events = [EpollEvent::empty(); 1];
+    fn get_status_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStatus> + Send>> {
+        self.sender.status_stream()
+    }
 
-        while !cancel_token.is_cancelled() {
-            use nix::sys::fanotify::MaskFlags;
+    fn close(&self) -> bool {
+        use nix::sys::fanotify::{MarkFlags, MaskFlags};
 
-            events.fill(EpollEvent::empty());
-            let res = tokio::task::block_in_place(move || self.epoll.wait(&mut events, 16u8));
-            if let Err(e) = res {
-                println!("epoll failed {e}");
-                res?;
-            }
-            if res.ok().unwrap() > 0 {
-                let all_records = self.fanotify.read_events_with_info_records()?;
-                'outer: for (event, records) in all_records {
-                    let kind = if event.mask().contains(MaskFlags::FAN_ONDIR) {
-                        FileSystemTargetKind::Directory
-                    } else {
-                        FileSystemTargetKind::File
-                    };
-                    // Handle Moves/Renames separately
-                    if event.mask().contains(MaskFlags::FAN_RENAME) {
-                        let mut moved_from = None;
-                        let mut moved_to = None;
-                        for record in records {
-                            if let FanotifyInfoRecord::Fid(record) = record {
-                                let path = {
-                                    let path = get_path_from_record(&record);
-                                    if let Err(e) = path {
-                                        if e == Errno::ESTALE {
-                                            break;
-                                        }
-                                        println!("another error occurred ${e}");
-                                    }
-                                    path?
-                                };
-                                if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME {
-                                    moved_from = Some(path);
-                                } else if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME {
-                                    moved_to = Some(path);
-                                }
-                            }
-                        }
+        if self.cancellation_token.is_cancelled() {
+            return true;
+        }
 
-                        if moved_from.is_none() || moved_to.is_none() {
-                            let tracer_event = FileSystemEvent {
-                                event_type: FileSystemEventType::Move,
-                                target: Some(FileSystemTarget {
-                                    path: moved_from.or(moved_to).unwrap_or(OsString::new()),
-                                    kind,
-                                }),
-                            };
-                            if let Err(_) = sender.send(tracer_event) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
-                        } else {
-                            let tracer_event1 = FileSystemEvent {
-                                event_type: FileSystemEventType::MovedTo(moved_to.clone().unwrap()),
-                                target: Some(FileSystemTarget {
-                                    path: moved_from.clone().unwrap(),
-                                    kind: kind.clone(),
-                                }),
-                            };
-
-                            let tracer_event2 = FileSystemEvent {
-                                event_type: FileSystemEventType::MovedFrom(moved_from.unwrap()),
-                                target: Some(FileSystemTarget {
-                                    path: moved_to.clone().unwrap(),
-                                    kind,
-                                }),
-                            };
-
-                            if let Err(_) = sender.send(tracer_event1) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
+        self.cancellation_token.cancel();
+        self.sender.stopped(None);
 
-                            if let Err(_) = sender.send(tracer_event2) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
-                        }
-                    } else {
-                        let mut tracer_event = FileSystemEvent {
-                            event_type: match event.mask() {
-                                x if x.contains(MaskFlags::FAN_CREATE) => {
-                                    FileSystemEventType::Create
-                                }
-                                x if x.contains(MaskFlags::FAN_DELETE_SELF) => {
-                                    FileSystemEventType::Delete
-                                }
-                                x if x.contains(MaskFlags::FAN_DELETE) => {
-                                    FileSystemEventType::Delete
-                                }
-                                x if x.contains(MaskFlags::FAN_MODIFY) => {
-                                    FileSystemEventType::Modify
-                                }
-                                x if x.contains(MaskFlags::FAN_MOVE_SELF) => {
-                                    FileSystemEventType::Move
-                                }
-                                x => {
-                                    eprintln!("Unknown Mask Received - {:?}", x);
-                                    FileSystemEventType::Unknown
-                                }
-                            },
-                            target: None,
-                        };
-                        let mut path = None;
-                        for record in records {
-                            if let FanotifyInfoRecord::Fid(record) = record {
-                                path = Some({
-                                    let path = get_path_from_record(&record);
-                                    if let Err(e) = path {
-                                        if e == Errno::ESTALE {
-                                            continue 'outer;
-                                        }
-                                        println!("another error occurred ${e}");
+        #[allow(non_snake_case)]
+        let MARK_FLAGS = MarkFlags::FAN_MARK_FLUSH;
+
+        let mut has_error = false;
+
+        if let Err(e) = self
+            .fanotify
+            .mark(MARK_FLAGS, MaskFlags::empty(), AT_FDCWD, Some("/"))
+        {
+            self.sender
+                .warn(KanshiError::file_system(FileSystemOperation::Unmark, None, e));
+            has_error = true;
+        }
+        !has_error
+    }
+}
+
+impl FanotifyTracer {
+    /// Marks `root`, which is `depth` levels below its watch, and the directories
+    /// below it the recursion mode allows.
+    fn mark_tree(&self, root: &Path, depth: usize) -> Result<usize, KanshiError> {
+        if self.sender.filter().excludes(root, depth, root.is_dir()) {
+            return Ok(0);
+        }
+        let flags = tree_flags(self.event_mask);
+        // The watch being extended, if any, to tell which marks this call added.
+        let owner = self.sender.watches().owner(root);
+        let mark_top_dir = mark(&self.fanotify, root, flags);
+
+        if let Ok(_) = mark_top_dir {
+            let mut marked = vec![root.to_path_buf()];
+            let mut linked: Vec<PathBuf> = Vec::new();
+            let mut directories = 1;
+            let mut traversal_queue = VecDeque::from([(root.to_path_buf(), depth)]);
+            let mut visited = ancestor_ids(root);
+
+            'outer: loop {
+                if let Some((next_dir, depth)) = traversal_queue.pop_front() {
+                    self.sender.filter().load_ignore_files(&next_dir, depth);
+                    if !self.recursion.includes(depth + 1) {
+                        continue;
+                    }
+                    if let Ok(dir_items) = fs::read_dir(next_dir) {
+                        for dir_item in dir_items {
+                            if let Ok(dir_item_unwrapped) = dir_item {
+                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
+                                    let path = dir_item_unwrapped.path();
+                                    let is_symlink = metadata.is_symlink();
+                                    let Some(metadata) =
+                                        traversed_dir(&path, metadata, self.follow_symlinks)
+                                    else {
+                                        continue;
+                                    };
+                                    if self.sender.filter().excludes(&path, depth + 1, true) {
+                                        continue;
                                     }
-                                    path?
-                                });
-                            }
-                        }
-                        if path.is_some() && path.as_ref().unwrap().len() > 0 {
-                            if event.mask().contains(MaskFlags::FAN_CREATE)
-                                && kind == FileSystemTargetKind::Directory
-                            {
-                                let path = Path::new(path.as_ref().unwrap());
-
-                                // Add new directory to fanotify
-                                if let Err(err) = mark(&self.fanotify, path) {
-                                    // We ignore ENOENT errors as it likely means a file was immediately created and deleted
-                                    if let KanshiError::FileSystemError(e) = err.clone() {
-                                        if !e.contains("ENOENT") {
-                                            return Err(err);
+                                    if visited.insert(dir_id(&metadata)) {
+                                        if let Err(e) = mark(&self.fanotify, &path, flags) {
+                                            for link in &linked {
+                                                self.links.remove(link);
+                                            }
+                                            self.unmark_added(&marked, owner);
+                                            return Err(e);
+                                        }
+                                        marked.push(path.clone());
+                                        if is_symlink {
+                                            if let Ok(target) = fs::canonicalize(&path) {
+                                                self.links.add(path.clone(), target);
+                                                linked.push(path.clone());
+                                            }
                                         }
+                                        directories += 1;
+                                        traversal_queue.push_back((path, depth + 1));
                                     }
                                 }
+                            } else {
+                                break 'outer;
                             }
-                            tracer_event.target = Some(FileSystemTarget {
-                                kind: kind.clone(),
-                                path: path.unwrap(),
-                            });
                         }
+                    } else {
+                        break 'outer;
+                    }
+                } else {
+                    break 'outer;
+                }
+            }
 
-                        if let Err(_) = sender.send(tracer_event) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+            Ok(directories)
+        } else {
+            mark_top_dir.map(|_| 0)
+        }
+    }
+
+    /// Removes the marks added by a `mark_tree()` that failed. Directories that belong
+    /// to another watch were marked before it, so they keep theirs.
+    fn unmark_added(&self, marked: &[PathBuf], owner: Option<u64>) {
+        let flags = tree_flags(self.event_mask);
+        for dir in marked {
+            if self.sender.watches().owner(dir) == owner {
+                // The error that failed `mark_tree()` is the one reported.
+                let _ = self.unmark_dir(dir, flags);
+            }
+        }
+    }
+
+    /// Fanotify does not keep a list of marks, so the tree is walked again. Every
+    /// directory below `root` was marked by `watch()` or when it was created.
+    fn unmark_tree(&self, root: &Path, depth: usize) -> Result<usize, KanshiError> {
+        // Another watch contains it, and everything below it.
+        let watches = self.sender.watches();
+        if watches.owner(root).is_some() {
+            return Ok(0);
+        }
+        let flags = tree_flags(self.event_mask);
+        self.unmark_dir(root, flags)?;
+        let mut unmarked = 1;
+
+        let mut traversal_queue = VecDeque::from([(root.to_path_buf(), depth)]);
+        let mut visited = ancestor_ids(root);
+
+        while let Some((next_dir, depth)) = traversal_queue.pop_front() {
+            if !self.recursion.includes(depth + 1) {
+                continue;
+            }
+            let Ok(dir_items) = fs::read_dir(next_dir) else {
+                continue;
+            };
+
+            for dir_item in dir_items.flatten() {
+                let Ok(metadata) = dir_item.metadata() else {
+                    continue;
+                };
+                let path = dir_item.path();
+                let Some(metadata) = traversed_dir(&path, metadata, self.follow_symlinks) else {
+                    continue;
+                };
+
+                if watches.owner(&path).is_some() {
+                    continue;
+                }
+                if visited.insert(dir_id(&metadata)) {
+                    match self.unmark_dir(&path, flags) {
+                        Ok(_) => unmarked += 1,
+                        // Directories that are already gone or were never marked have nothing to remove.
+                        Err(KanshiError::FileSystemError(e))
+                            if e.kind == io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(err),
                     }
+                    traversal_queue.push_back((path, depth + 1));
                 }
             }
         }
 
+        Ok(unmarked)
+    }
+
+    /// Removes `flags` from `dir`, except for what the files watched in it need.
+    fn unmark_dir(&self, dir: &Path, flags: MaskFlags) -> Result<(), KanshiError> {
+        // Files watched on their own are known by their canonical path.
+        let is_file_parent = fs::canonicalize(dir).is_ok_and(|dir| self.files.is_parent(&dir));
+        if is_file_parent {
+            unmark(&self.fanotify, dir, flags.difference(FILE_PARENT_FLAGS))
+        } else {
+            unmark(&self.fanotify, dir, flags)
+        }
+    }
+
+    /// Marks a directory moved into a watched tree, and what is below it, and unmarks
+    /// one moved out of it, as a create or delete of a directory would.
+    fn move_tree(&self, from: Option<&Path>, to: Option<&Path>) -> Result<(), KanshiError> {
+        let watches = self.sender.watches();
+        let depth = |path: Option<&Path>| path.and_then(|path| watches.depth(path));
+        let moved = match (from, depth(from), to, depth(to)) {
+            (_, None, Some(to), Some(depth)) if self.recursion.includes(depth) => self
+                .mark_tree(to, depth)
+                .map(|marked| watches.marked(to, marked)),
+            // The marks are on the directories themselves, which are at `to` now.
+            (Some(from), Some(depth), Some(to), None) => self
+                .unmark_tree(to, depth)
+                .map(|unmarked| watches.unmarked(from, unmarked)),
+            _ => Ok(()),
+        };
+        match moved {
+            // It was moved again or removed, which its own event handles.
+            Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => Ok(()),
+            moved => moved,
+        }
+    }
+
+    /// Marks the directories a changed ignore file at `path` no longer ignores.
+    fn reload_ignore_files(&self, path: &Path) -> Result<(), KanshiError> {
+        for (dir, depth) in self.sender.filter().reload_ignore_files(path) {
+            let marked = self.mark_tree(&dir, depth)?;
+            self.sender.watches().marked(&dir, marked);
+        }
         Ok(())
     }
 
-    fn close(&self) -> bool {
-        use nix::sys::fanotify::{MarkFlags, MaskFlags};
+    /// Called when a symlink may have appeared at, disappeared from or been pointed
+    /// elsewhere at `link`. The directory it pointed to is unmarked, and the one it
+    /// points to now is marked unless that closes a cycle.
+    fn relink(&self, link: &Path) -> Result<(), KanshiError> {
+        let watches = self.sender.watches();
+        if let Some(target) = self.links.remove(link) {
+            let depth = watches.depth(link).unwrap_or(0);
+            match self.unmark_tree(&target, depth) {
+                Ok(unmarked) => watches.unmarked(link, unmarked),
+                // The old target is gone, and its marks with it.
+                Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        if self.cancellation_token.is_cancelled() {
-            return true;
+        let Ok(metadata) = fs::symlink_metadata(link) else {
+            return Ok(());
+        };
+        if !metadata.is_symlink() {
+            return Ok(());
+        }
+        let Some(target) = traversed_dir(link, metadata, true) else {
+            return Ok(());
+        };
+        let Some(depth) = watches
+            .depth(link)
+            .filter(|depth| self.recursion.includes(*depth))
+        else {
+            return Ok(());
+        };
+        if link
+            .parent()
+            .is_some_and(|parent| ancestor_ids(parent).contains(&dir_id(&target)))
+        {
+            return Ok(());
         }
 
-        self.cancellation_token.cancel();
+        if let Ok(target) = fs::canonicalize(link) {
+            self.links.add(link.to_path_buf(), target);
+        }
+        match self.mark_tree(link, depth) {
+            Ok(marked) => watches.marked(link, marked),
+            // The link was removed or replaced again. The next event will tell.
+            Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => {
+                self.links.remove(link);
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
 
-        #[allow(non_snake_case)]
-        let MARK_FLAGS = MarkFlags::FAN_MARK_FLUSH;
+    /// Marks a single file, and its directory to notice the file being replaced.
+    fn watch_file(&self, file: PathBuf) -> Result<WatchHandle, KanshiError> {
+        let flags = file_flags(self.event_mask);
+        if flags.is_empty() {
+            return Err(KanshiError::InvalidParameter(
+                "the event mask selects no events for a single file".to_owned(),
+            ));
+        }
+        mark(&self.fanotify, &file, flags)?;
 
-        let mut has_error = false;
+        let watches = self.sender.watches();
+        if let Some(parent) = file.parent() {
+            // A directory in a watched tree already reports creates and renames.
+            if watches.owner(parent).is_none() {
+                mark(&self.fanotify, parent, FILE_PARENT_FLAGS)?;
+            }
+        }
 
-        if self.epoll.delete(self.fanotify.as_fd()).is_err() {
-            println!("epoll.delete returned error");
-            has_error = true;
+        // Paths resolved from file handles are canonical.
+        if let Some(canonical) = canonical_file(&file) {
+            self.files.add(&canonical);
         }
-        if self
-            .fanotify
-            .mark(MARK_FLAGS, MaskFlags::empty(), AT_FDCWD, Some("/"))
-            .is_err()
-        {
-            println!("fanotify.mark returned error");
-            has_error = true;
+        Ok(watches.add(file, 0, KanshiEngines::Fanotify))
+    }
+
+    fn unwatch_file(&self, file: &Path, canonical: &Path) -> Result<(), KanshiError> {
+        match unmark(&self.fanotify, file, file_flags(self.event_mask)) {
+            // The file was replaced or deleted, so its mark is gone already.
+            Ok(_) => {}
+            Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
         }
-        !has_error
+
+        let watches = self.sender.watches();
+        watches.remove(file);
+        if let Some(parent) = self.files.remove(canonical) {
+            if watches.owner(&parent).is_none() {
+                unmark(&self.fanotify, &parent, FILE_PARENT_FLAGS)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Called when a watched file's name starts or stops pointing at an inode, for
+    /// example when an editor renames a new version over it. A file renamed away
+    /// keeps its mark, so it is removed from where the file went.
+    fn retarget_file(
+        &self,
+        file: &Path,
+        moved_to: Option<&Path>,
+        arrived: bool,
+    ) -> Result<(), KanshiError> {
+        if !self.files.contains(file) {
+            return Ok(());
+        }
+
+        let result = if arrived {
+            mark(&self.fanotify, file, file_flags(self.event_mask))
+        } else if let Some(moved_to) = moved_to {
+            unmark(&self.fanotify, moved_to, file_flags(self.event_mask))
+        } else {
+            Ok(())
+        };
+
+        match result {
+            // It was already removed or replaced again. The next event will tell.
+            Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn read_events_loop(&self) -> Result<(), KanshiError> {
+        read_loop(self, &self.cancellation_token).await
+    }
+}
+
+impl Reader for FanotifyTracer {
+    type Records = Vec<(FanotifyEvent, Vec<FanotifyInfoRecord>)>;
+    type State = MetadataCache;
+
+    fn sender(&self) -> &EventSender {
+        &self.sender
+    }
+
+    fn fd(&self) -> BorrowedFd<'_> {
+        self.fanotify.as_fd()
+    }
+
+    fn read_records(&self) -> io::Result<Self::Records> {
+        self.fanotify
+            .read_events_with_info_records()
+            .map_err(io::Error::from)
+    }
+
+    async fn handle(
+        &self,
+        records: Self::Records,
+        state: &mut MetadataCache,
+    ) -> Result<(), KanshiError> {
+        let sender = &self.sender;
+        let timestamp = SystemTime::now();
+        'outer: for (event, records) in records {
+            if event.mask().contains(MaskFlags::FAN_Q_OVERFLOW) {
+                if sender
+                    .send(FileSystemEvent::overflow(None, None, timestamp))
+                    .await
+                    .is_err()
+                {
+                    return Err(KanshiError::StreamClosedError);
+                }
+                continue;
+            }
+
+            let kind = if event.mask().contains(MaskFlags::FAN_ONDIR) {
+                FileSystemTargetKind::Directory
+            } else {
+                FileSystemTargetKind::File
+            };
+            // Handle Moves/Renames separately
+            if event.mask().contains(MaskFlags::FAN_RENAME) {
+                let mut moved_from = None;
+                let mut moved_to = None;
+                for record in records {
+                    if let FanotifyInfoRecord::Fid(record) = record {
+                        let path = match get_path_from_record(&record) {
+                            Ok(path) => self.links.rewrite(path),
+                            // Usually ESTALE, the item is already gone. With only one side
+                            // of the rename, it would look like a move out of or into the
+                            // tree, so the whole event is skipped.
+                            Err(e) => {
+                                self.sender.warn(KanshiError::file_system(
+                                    FileSystemOperation::ResolveHandle,
+                                    None,
+                                    e,
+                                ));
+                                continue 'outer;
+                            }
+                        };
+                        if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME {
+                            moved_from = Some(path);
+                        } else if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME {
+                            moved_to = Some(path);
+                        }
+                    }
+                }
+
+                let watches = sender.watches();
+                let wanted = [&moved_from, &moved_to].into_iter().flatten().any(|path| {
+                    self.files.wants(Path::new(path), watches)
+                });
+                if !wanted {
+                    continue;
+                }
+                if let Some(path) = &moved_from {
+                    self.retarget_file(Path::new(path), moved_to.as_deref(), false)?;
+                }
+                if let Some(path) = &moved_to {
+                    self.retarget_file(Path::new(path), None, true)?;
+                }
+                if kind == FileSystemTargetKind::Directory {
+                    self.move_tree(moved_from.as_deref(), moved_to.as_deref())?;
+                }
+                if self.follow_symlinks && kind != FileSystemTargetKind::Directory {
+                    for path in [&moved_from, &moved_to].into_iter().flatten() {
+                        self.relink(Path::new(path))?;
+                    }
+                }
+                for path in [&moved_from, &moved_to].into_iter().flatten() {
+                    self.reload_ignore_files(Path::new(path))?;
+                }
+
+                if moved_from.is_none() || moved_to.is_none() {
+                    let direction = if moved_from.is_some() {
+                        MoveDirection::Out
+                    } else {
+                        MoveDirection::In
+                    };
+                    let path = moved_from.or(moved_to).unwrap_or_default();
+                    let kind = kind.resolve(&path);
+                    let tracer_event = FileSystemEvent::moved(
+                        direction,
+                        FileSystemTarget::new(path, kind),
+                        timestamp,
+                    );
+                    if let Err(_) = sender.send(tracer_event).await {
+                        return Err(KanshiError::StreamClosedError);
+                    }
+                } else {
+                    let moved_to = moved_to.unwrap();
+                    let kind = kind.resolve(&moved_to);
+                    let tracer_events = FileSystemEvent::rename(
+                        moved_from.unwrap(),
+                        moved_to,
+                        kind,
+                        timestamp,
+                        self.split_renames,
+                    );
+
+                    for tracer_event in tracer_events {
+                        if sender.send(tracer_event).await.is_err() {
+                            return Err(KanshiError::StreamClosedError);
+                        }
+                    }
+                }
+            } else {
+                let event_types = event_types(event.mask());
+                let mut path = None;
+                for record in records {
+                    if let FanotifyInfoRecord::Fid(record) = record {
+                        path = match get_path_from_record(&record) {
+                            Ok(path) => Some(self.links.rewrite(path)),
+                            // Usually ESTALE, the item is already gone. Skip it.
+                            Err(e) => {
+                                self.sender.warn(KanshiError::file_system(
+                                    FileSystemOperation::ResolveHandle,
+                                    None,
+                                    e,
+                                ));
+                                continue 'outer;
+                            }
+                        };
+                    }
+                }
+                if let Some(path) = path.as_deref().filter(|path| !path.as_os_str().is_empty()) {
+                    if !self.files.wants(Path::new(path), sender.watches()) {
+                        continue;
+                    }
+                    if event.mask().contains(MaskFlags::FAN_CREATE)
+                        && kind != FileSystemTargetKind::Directory
+                    {
+                        self.retarget_file(Path::new(path), None, true)?;
+                    }
+                    if self.follow_symlinks
+                        && kind != FileSystemTargetKind::Directory
+                        && event
+                            .mask()
+                            .intersects(MaskFlags::FAN_CREATE | MaskFlags::FAN_DELETE)
+                    {
+                        self.relink(Path::new(path))?;
+                    }
+                    self.reload_ignore_files(Path::new(path))?;
+                }
+                let depth = path
+                    .as_deref()
+                    .and_then(|path| sender.watches().depth(Path::new(path)));
+                if path.is_some() && path.as_ref().unwrap().as_os_str().len() > 0 {
+                    if event.mask().contains(MaskFlags::FAN_CREATE)
+                        && kind == FileSystemTargetKind::Directory
+                        && depth.is_some_and(|depth| {
+                            self.recursion.includes(depth)
+                                && !sender
+                                    .filter()
+                                    .excludes(Path::new(path.as_ref().unwrap()), depth, true)
+                        })
+                    {
+                        let path = Path::new(path.as_ref().unwrap());
+
+                        // Add new directory to fanotify
+                        if let Err(err) =
+                            mark(&self.fanotify, path, tree_flags(self.event_mask))
+                        {
+                            // We ignore ENOENT errors as it likely means a file was immediately created and deleted
+                            if let KanshiError::FileSystemError(e) = &err {
+                                if e.kind != io::ErrorKind::NotFound {
+                                    return Err(err);
+                                }
+                            }
+                        } else {
+                            sender.watches().marked(path, 1);
+                        }
+                    }
+                } else {
+                    path = None;
+                }
+
+                let target = path.map(|path| FileSystemTarget::new(&path, kind.resolve(&path)));
+                for mut event_type in event_types {
+                    if let Some(target) = &target {
+                        state.update(&mut event_type, &target.path);
+                    }
+                    let tracer_event =
+                        FileSystemEvent::new(event_type, target.clone(), timestamp);
+                    if sender.send(tracer_event).await.is_err() {
+                        return Err(KanshiError::StreamClosedError);
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -359,27 +753,129 @@ impl Drop for FanotifyTracer {
     }
 }
 
-fn mark(fanotify: &Fanotify, path: &Path) -> Result<(), KanshiError> {
-    use nix::sys::fanotify::{MarkFlags, MaskFlags};
+fn mark(fanotify: &Fanotify, path: &Path, mask_flags: MaskFlags) -> Result<(), KanshiError> {
+    use nix::sys::fanotify::MarkFlags;
     #[allow(non_snake_case)]
     let MARK_FLAGS = MarkFlags::FAN_MARK_ADD;
+
+    if let Err(e) = fanotify.mark(MARK_FLAGS, mask_flags, AT_FDCWD, Some(path)) {
+        Err(KanshiError::file_system(
+            FileSystemOperation::Mark,
+            Some(path),
+            e,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn unmark(fanotify: &Fanotify, path: &Path, mask_flags: MaskFlags) -> Result<(), KanshiError> {
+    use nix::sys::fanotify::MarkFlags;
     #[allow(non_snake_case)]
-    let MASK_FLAGS = MaskFlags::FAN_ONDIR
+    let MARK_FLAGS = MarkFlags::FAN_MARK_REMOVE;
+
+    if let Err(e) = fanotify.mark(MARK_FLAGS, mask_flags, AT_FDCWD, Some(path)) {
+        Err(KanshiError::file_system(
+            FileSystemOperation::Unmark,
+            Some(path),
+            e,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Directory of a file watched on its own, marked to notice the file being replaced.
+const FILE_PARENT_FLAGS: MaskFlags = MaskFlags::FAN_EVENT_ON_CHILD
+    .union(MaskFlags::FAN_CREATE)
+    .union(MaskFlags::FAN_RENAME)
+    .union(MaskFlags::FAN_DELETE);
+
+/// The canonical form of `file`, as fanotify reports it, keeping the file name
+/// itself so a symlink is not followed.
+fn canonical_file(file: &Path) -> Option<PathBuf> {
+    let parent = file.parent()?.canonicalize().ok()?;
+    Some(parent.join(file.file_name()?))
+}
+
+fn tree_flags(event_mask: EventMask) -> MaskFlags {
+    // Creates and renames are always needed to keep new and renamed directories marked.
+    // They are dropped by the sender if they were not selected.
+    let mut mask_flags = MaskFlags::FAN_ONDIR
         | MaskFlags::FAN_EVENT_ON_CHILD
         | MaskFlags::FAN_CREATE
-        | MaskFlags::FAN_MODIFY
-        | MaskFlags::FAN_DELETE
-        | MaskFlags::FAN_RENAME;
+        | MaskFlags::FAN_RENAME
+        | file_flags(event_mask);
 
-    if let Err(e) = fanotify.mark(MARK_FLAGS, MASK_FLAGS, AT_FDCWD, Some(path)) {
-        Err(KanshiError::FileSystemError(e.to_string()))
-    } else {
-        Ok(())
+    if event_mask.contains(EventMask::DELETE) {
+        mask_flags |= MaskFlags::FAN_DELETE;
+    }
+
+    mask_flags
+}
+
+/// Flags for a mark on a file. Directory entry events are rejected on files.
+fn file_flags(event_mask: EventMask) -> MaskFlags {
+    let mut mask_flags = MaskFlags::empty();
+
+    for (class, flags) in [
+        (EventMask::MODIFY, MaskFlags::FAN_MODIFY),
+        (EventMask::CLOSE_WRITE, MaskFlags::FAN_CLOSE_WRITE),
+        (EventMask::CLOSE_NOWRITE, MaskFlags::FAN_CLOSE_NOWRITE),
+        (EventMask::OPEN, MaskFlags::FAN_OPEN),
+        (EventMask::ACCESS, MaskFlags::FAN_ACCESS),
+        (EventMask::ATTRIB, MaskFlags::FAN_ATTRIB),
+    ] {
+        if event_mask.contains(class) {
+            mask_flags |= flags;
+        }
+    }
+
+    mask_flags
+}
+
+/// Fanotify merges events that happen on the same object before they are read,
+/// so a single record can carry several event types.
+fn event_types(mask: MaskFlags) -> Vec<FileSystemEventType> {
+    let mut event_types = Vec::new();
+
+    if mask.contains(MaskFlags::FAN_CREATE) {
+        event_types.push(FileSystemEventType::Create);
+    }
+    if mask.contains(MaskFlags::FAN_OPEN) {
+        event_types.push(FileSystemEventType::Open);
+    }
+    if mask.contains(MaskFlags::FAN_ACCESS) {
+        event_types.push(FileSystemEventType::Access);
+    }
+    if mask.contains(MaskFlags::FAN_MODIFY) {
+        event_types.push(FileSystemEventType::Modify);
     }
+    if mask.contains(MaskFlags::FAN_ATTRIB) {
+        event_types.push(FileSystemEventType::Metadata(None));
+    }
+    if mask.contains(MaskFlags::FAN_CLOSE_WRITE) {
+        event_types.push(FileSystemEventType::CloseWrite);
+    }
+    if mask.contains(MaskFlags::FAN_CLOSE_NOWRITE) {
+        event_types.push(FileSystemEventType::CloseNoWrite);
+    }
+    if mask.contains(MaskFlags::FAN_MOVE_SELF) {
+        event_types.push(FileSystemEventType::Move);
+    }
+    if mask.intersects(MaskFlags::FAN_DELETE | MaskFlags::FAN_DELETE_SELF) {
+        event_types.push(FileSystemEventType::Delete);
+    }
+
+    if event_types.is_empty() {
+        event_types.push(FileSystemEventType::Unknown);
+    }
+
+    event_types
 }
 
-fn get_path_from_record(record: &FanotifyFidRecord) -> Result<OsString, Errno> {
-    let mut path = OsString::new();
+fn get_path_from_record(record: &FanotifyFidRecord) -> Result<PathBuf, Errno> {
+    let mut path = PathBuf::new();
 
     let handle = &record.handle();
     let fh = handle.as_ptr() as *mut FileHandle;
@@ -404,7 +900,6 @@ fn get_path_from_record(record: &FanotifyFidRecord) -> Result<OsString, Errno> {
 
     if let Some(name) = file_name {
         if name != "." {
-            path.push("/");
             path.push(name);
         }
     }
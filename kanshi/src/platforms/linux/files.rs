@@ -0,0 +1,72 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::watches::WatchRegistry;
+
+/// Files watched on their own. Besides the file itself, its directory is marked
+/// so that replacing the file by renaming another one over it is noticed. Events
+/// from such a directory are dropped unless they belong to a watch.
+#[derive(Clone, Default)]
+pub(crate) struct FileWatches {
+    // Watched file names, by directory.
+    parents: Arc<Mutex<HashMap<PathBuf, HashSet<OsString>>>>,
+}
+
+impl FileWatches {
+    pub(crate) fn add(&self, file: &Path) {
+        if let (Some(parent), Some(name)) = (file.parent(), file.file_name()) {
+            let mut parents = self.parents.lock().unwrap();
+            parents
+                .entry(parent.to_path_buf())
+                .or_default()
+                .insert(name.to_owned());
+        }
+    }
+
+    /// Stops watching `file`. Returns its directory once no other file in it is watched.
+    pub(crate) fn remove(&self, file: &Path) -> Option<PathBuf> {
+        let (parent, name) = (file.parent()?, file.file_name()?);
+        let mut parents = self.parents.lock().unwrap();
+        let names = parents.get_mut(parent)?;
+        names.remove(name);
+
+        if names.is_empty() {
+            parents.remove(parent);
+            Some(parent.to_path_buf())
+        } else {
+            None
+        }
+    }
+
+    /// Whether `path` is a file watched on its own.
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return false;
+        };
+
+        let parents = self.parents.lock().unwrap();
+        parents
+            .get(parent)
+            .is_some_and(|names| names.contains(name))
+    }
+
+    /// Whether `dir` is marked for the files watched in it.
+    pub(crate) fn is_parent(&self, dir: &Path) -> bool {
+        self.parents.lock().unwrap().contains_key(dir)
+    }
+
+    /// Whether an event for `path` should be reported. Siblings of a watched file
+    /// are only reported if they are part of a watched tree.
+    pub(crate) fn wants(&self, path: &Path, watches: &WatchRegistry) -> bool {
+        let Some(parent) = path.parent() else {
+            return true;
+        };
+
+        let is_file_parent = self.parents.lock().unwrap().contains_key(parent);
+        !is_file_parent || watches.owner(path).is_some()
+    }
+}
@@ -1,132 +1,150 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    ffi::OsString,
-    fs,
-    os::{
-        fd::{AsFd, AsRawFd},
-        unix::fs::MetadataExt,
-    },
+    collections::{HashMap, VecDeque},
+    fs, io,
+    os::fd::{AsFd, BorrowedFd},
     path::{self, Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use async_stream::stream;
-use futures::io;
-use nix::sys::{
-    epoll::Epoll,
-    inotify::{Inotify, InotifyEvent, WatchDescriptor},
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, Inotify, InotifyEvent, WatchDescriptor},
 };
 use tokio::sync::{broadcast::error::RecvError, Mutex};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiImpl,
-    KanshiError,
+    channel::EventSender,
+    filter::PathFilter,
+    metadata::MetadataCache,
+    EventMask, FileSystemEvent, FileSystemEventType, FileSystemOperation, FileSystemTarget,
+    FileSystemTargetKind, KanshiImpl, KanshiError, KanshiStatus, MoveDirection, RecursionMode,
+    WatchHandle,
 };
 
-use super::KanshiOptions;
+use super::{
+    files::FileWatches,
+    links::{ancestor_ids, dir_id, traversed_dir},
+    reader::{read_loop, Reader},
+    KanshiEngines, KanshiOptions,
+};
 
 #[derive(Clone)]
 pub struct INotifyTracer {
     inotify: Arc<Inotify>,
-    epoll: Arc<Epoll>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    sender: EventSender,
     cancellation_token: CancellationToken,
+    split_renames: bool,
+    event_mask: EventMask,
+    recursion: RecursionMode,
+    follow_symlinks: bool,
     watch_descriptors: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>>,
+    files: FileWatches,
 }
 
 impl KanshiImpl<KanshiOptions> for INotifyTracer {
-    fn new(_opts: KanshiOptions) -> Result<INotifyTracer, KanshiError> {
-        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
+    fn new(opts: KanshiOptions) -> Result<INotifyTracer, KanshiError> {
         use nix::sys::inotify::InitFlags;
 
+        // Non-blocking, so the reader can wait for it on the runtime's reactor.
         #[allow(non_snake_case)]
-        let INIT_FLAGS = InitFlags::IN_CLOEXEC;
+        let INIT_FLAGS = InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK;
 
         let inotify_fd = Inotify::init(INIT_FLAGS);
 
         if let Ok(inotify) = inotify_fd {
-            // Setup epoll
-            let epoll_event =
-                EpollEvent::new(EpollFlags::EPOLLIN, inotify.as_fd().as_raw_fd() as u64);
+            let tx = EventSender::new(
+                opts.event_buffer_capacity,
+                opts.backpressure,
+                opts.event_mask,
+                opts.root_paths,
+                PathFilter::new(&opts.include, &opts.exclude, opts.respect_ignore_files)?,
+            )?;
+            Ok(INotifyTracer {
+                inotify: Arc::new(inotify),
+                sender: tx,
+                cancellation_token: CancellationToken::new(),
+                split_renames: opts.split_renames,
+                event_mask: opts.event_mask,
+                recursion: opts.recursion,
+                follow_symlinks: opts.follow_symlinks,
+                watch_descriptors: Arc::new(Mutex::new(HashMap::new())),
+                files: FileWatches::default(),
+            })
+        } else {
+            let e = inotify_fd.err().unwrap();
+            Err(KanshiError::file_system(FileSystemOperation::Init, None, e))
+        }
+    }
 
-            let epoll_fd = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC);
+    async fn watch(&self, dir: impl AsRef<Path>) -> Result<WatchHandle, KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosedError);
+        }
 
-            if let Ok(epoll) = epoll_fd {
-                if let Err(e) = epoll.add(inotify.as_fd(), epoll_event) {
-                    Err(KanshiError::FileSystemError(e.to_string()))
-                } else {
-                    let (tx, _rx) = tokio::sync::broadcast::channel(32);
-                    Ok(INotifyTracer {
-                        inotify: Arc::new(inotify),
-                        epoll: Arc::new(epoll),
-                        sender: tx,
-                        cancellation_token: CancellationToken::new(),
-                        watch_descriptors: Arc::new(Mutex::new(HashMap::new())),
-                    })
-                }
-            } else {
-                let e = epoll_fd.err().unwrap();
-                Err(KanshiError::FileSystemError(e.to_string()))
-            }
-        } else {
-            Err(KanshiError::FileSystemError(
-                io::Error::last_os_error().to_string(),
-            ))
+        let dir = dir.as_ref();
+        let absolute_path = path::absolute(dir).map_err(|e| {
+            KanshiError::file_system(FileSystemOperation::Traverse, Some(dir), e)
+        })?;
+        if absolute_path.is_file() {
+            return self.watch_file(absolute_path).await;
         }
+
+        let directories = self.mark_tree(&absolute_path, 0).await?;
+
+        Ok(self
+            .sender
+            .watches()
+            .add(absolute_path, directories, KanshiEngines::Inotify))
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), crate::KanshiError> {
+    async fn unwatch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
         if self.cancellation_token.is_cancelled() {
             return Err(KanshiError::StreamClosedError);
         }
 
-        let absolute_path = path::absolute(Path::new(dir))?;
-        let mut watchers = self.watch_descriptors.lock().await;
-        let mark_top_dir = mark(&self.inotify, &mut watchers, absolute_path.as_path());
+        let dir = dir.as_ref();
+        let absolute_path = path::absolute(dir).map_err(|e| {
+            KanshiError::file_system(FileSystemOperation::Traverse, Some(dir), e)
+        })?;
+        if self.files.contains(&absolute_path) {
+            return self.unwatch_file(&absolute_path).await;
+        }
 
-        if let Ok(_) = mark_top_dir {
-            let mut traversal_queue = VecDeque::from([absolute_path]);
-            let mut visited = HashSet::<u64>::new();
+        let watches = self.sender.watches();
+        if !watches.remove(&absolute_path) {
+            return Err(KanshiError::file_system(
+                FileSystemOperation::Unmark,
+                Some(&absolute_path),
+                Errno::ENOENT,
+            ));
+        }
 
-            'outer: loop {
-                if let Some(next_dir) = traversal_queue.pop_front() {
-                    if let Ok(dir_items) = fs::read_dir(next_dir) {
-                        for dir_item in dir_items {
-                            if let Ok(dir_item_unwrapped) = dir_item {
-                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
-                                    let inode_number = metadata.ino();
-                                    if !visited.contains(&inode_number) && !metadata.is_symlink() {
-                                        visited.insert(inode_number);
-                                        if dir_item_unwrapped.path().is_dir() {
-                                            if let Err(e) = mark(
-                                                &self.inotify,
-                                                &mut watchers,
-                                                &dir_item_unwrapped.path(),
-                                            ) {
-                                                return Err(e);
-                                            }
-                                            traversal_queue.push_back(dir_item_unwrapped.path());
-                                        }
-                                    }
-                                }
-                            } else {
-                                break 'outer;
-                            }
-                        }
-                    } else {
-                        break 'outer;
-                    }
-                } else {
-                    break 'outer;
-                }
+        // Descriptors below the tree that another watch contains are kept.
+        let mut watchers = self.watch_descriptors.lock().await;
+        let descriptors: Vec<(WatchDescriptor, PathBuf)> = watchers
+            .iter()
+            .filter(|(_, path)| path.starts_with(&absolute_path) && watches.owner(path).is_none())
+            .map(|(wd, path)| (*wd, path.clone()))
+            .collect();
+
+        for (wd, path) in descriptors {
+            if self.files.is_parent(&path) {
+                // Only what the files watched in it need is left.
+                mark(&self.inotify, &mut watchers, &path, FILE_PARENT_FLAGS)?;
+                continue;
             }
-
-            Ok(())
-        } else {
-            mark_top_dir
+            watchers.remove(&wd);
+            unmark(&self.inotify, &wd, &path)?;
         }
+        Ok(())
+    }
+
+    fn watches(&self) -> Vec<WatchHandle> {
+        self.sender.watches().list()
     }
 
     fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
@@ -144,7 +162,7 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                             Ok(x) => yield x,
                             Err(e) => match e {
                                 RecvError::Closed => break,
-                                _ => ()
+                                RecvError::Lagged(lost) => yield listener.lagged(lost),
                             }
                         }
                     }
@@ -153,296 +171,678 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
         })
     }
 
-    async fn start(&self) -> Result<(), crate::KanshiError> {
-        use nix::sys::epoll::EpollEvent;
+    async fn start(&self) -> Result<(), KanshiError> {
+        self.sender.run(self.read_events_loop()).await
+    }
 
-        let cancel_token = self.cancellation_token.clone();
-        let sender = self.sender.clone();
+    async fn ready(&self) -> Result<(), KanshiError> {
+        self.sender.ready().await
+    }
 
-        let mut events = [EpollEvent::empty(); 1];
-        let mut cookie_map: HashMap<u32, InotifyEvent> = HashMap::new();
-        // let mut cookie_map_old: HashMap<u32, InotifyEvent>;
+    fn get_status_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStatus> + Send>> {
+        self.sender.status_stream()
+    }
 
-        while !cancel_token.is_cancelled() {
-            use nix::sys::inotify::AddWatchFlags;
+    fn close(&self) -> bool {
+        if self.cancellation_token.is_cancelled() {
+            return true;
+        }
 
-            events.fill(EpollEvent::empty());
-            let res = tokio::task::block_in_place(move || self.epoll.wait(&mut events, 16u8));
+        self.cancellation_token.cancel();
+        self.sender.stopped(None);
 
-            if let Err(e) = res {
-                println!("epoll failed {e}");
-                res?;
-            }
+        // Inotify is automatically closed on drop.
 
-            if res.ok().unwrap() > 0 {
-                // cookie_map_old = cookie_map;
-                // cookie_map = HashMap::new();
+        true
+    }
+}
 
-                let all_records = self.inotify.read_events()?;
-                for record in all_records {
-                    let kind = if record.mask.contains(AddWatchFlags::IN_ISDIR) {
-                        FileSystemTargetKind::Directory
-                    } else {
-                        FileSystemTargetKind::File
-                    };
+impl INotifyTracer {
+    /// Marks `root`, which is `depth` levels below its watch, and the directories
+    /// below it the recursion mode allows.
+    async fn mark_tree(&self, root: &Path, depth: usize) -> Result<usize, KanshiError> {
+        if self.sender.filter().excludes(root, depth, root.is_dir()) {
+            return Ok(0);
+        }
+        let mut watchers = self.watch_descriptors.lock().await;
+        let flags = tree_flags(self.event_mask);
+        // Descriptors this call added, removed again if it fails partway through.
+        let mut added = Vec::new();
+        let mark_top_dir = mark_new(&self.inotify, &mut watchers, root, flags, &mut added);
 
-                    // Is a normal Inotify event
-                    if record.cookie == 0 {
-                        let mut wd = self.watch_descriptors.lock().await;
-                        let path = wd.get(&record.wd);
+        if let Ok(_) = mark_top_dir {
+            let mut directories = 1;
+            let mut traversal_queue = VecDeque::from([(root.to_path_buf(), depth)]);
+            let mut visited = ancestor_ids(root);
 
-                        if record.mask.contains(AddWatchFlags::IN_IGNORED) {
-                            continue;
+            'outer: loop {
+                if let Some((next_dir, depth)) = traversal_queue.pop_front() {
+                    self.sender.filter().load_ignore_files(&next_dir, depth);
+                    if !self.recursion.includes(depth + 1) {
+                        continue;
+                    }
+                    if let Ok(dir_items) = fs::read_dir(next_dir) {
+                        for dir_item in dir_items {
+                            if let Ok(dir_item_unwrapped) = dir_item {
+                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
+                                    let path = dir_item_unwrapped.path();
+                                    let Some(metadata) =
+                                        traversed_dir(&path, metadata, self.follow_symlinks)
+                                    else {
+                                        continue;
+                                    };
+                                    if self.sender.filter().excludes(&path, depth + 1, true) {
+                                        continue;
+                                    }
+                                    if visited.insert(dir_id(&metadata)) {
+                                        if let Err(e) = mark_new(
+                                            &self.inotify,
+                                            &mut watchers,
+                                            &path,
+                                            flags,
+                                            &mut added,
+                                        ) {
+                                            unmark_added(&self.inotify, &mut watchers, added);
+                                            return Err(e);
+                                        }
+                                        directories += 1;
+                                        traversal_queue.push_back((path, depth + 1));
+                                    }
+                                }
+                            } else {
+                                break 'outer;
+                            }
                         }
+                    } else {
+                        break 'outer;
+                    }
+                } else {
+                    break 'outer;
+                }
+            }
 
-                        let event_type = match record.mask {
-                            x if x.contains(AddWatchFlags::IN_CREATE) => {
-                                FileSystemEventType::Create
-                            }
-                            x if x.contains(AddWatchFlags::IN_DELETE) => {
-                                FileSystemEventType::Delete
-                            }
-                            x if x.contains(AddWatchFlags::IN_DELETE_SELF) => {
-                                FileSystemEventType::Delete
-                            }
-                            x if x.contains(AddWatchFlags::IN_MODIFY) => {
-                                FileSystemEventType::Modify
-                            }
-                            x if x.contains(AddWatchFlags::IN_ATTRIB) => {
-                                FileSystemEventType::Modify
-                            }
-                            x => {
-                                eprintln!("Unknown Mask Received - {:?}", x);
-                                FileSystemEventType::Unknown
-                            }
-                        };
+            Ok(directories)
+        } else {
+            mark_top_dir.map(|_| 0)
+        }
+    }
 
-                        let mut full_path = OsString::new();
-                        if let Some(path) = path {
-                            full_path.push(path.as_os_str());
-                        }
+    /// Marks a single file, and its directory to notice the file being replaced.
+    async fn watch_file(&self, file: PathBuf) -> Result<WatchHandle, KanshiError> {
+        let flags = file_flags(self.event_mask);
+        if flags.is_empty() {
+            return Err(KanshiError::InvalidParameter(
+                "the event mask selects no events for a single file".to_owned(),
+            ));
+        }
+        let mut watchers = self.watch_descriptors.lock().await;
+        let mut added = Vec::new();
+        mark_new(&self.inotify, &mut watchers, &file, flags, &mut added)?;
+
+        let watches = self.sender.watches();
+        if let Some(parent) = file.parent() {
+            // A directory in a watched tree already reports creates and renames.
+            if watches.owner(parent).is_none() {
+                let marked = mark(&self.inotify, &mut watchers, parent, FILE_PARENT_FLAGS);
+                if let Err(e) = marked {
+                    unmark_added(&self.inotify, &mut watchers, added);
+                    return Err(e);
+                }
+            }
+        }
 
-                        full_path.push("/");
+        self.files.add(&file);
+        Ok(watches.add(file, 0, KanshiEngines::Inotify))
+    }
 
-                        if let Some(name) = record.name {
-                            full_path.push(name);
-                        }
+    async fn unwatch_file(&self, file: &Path) -> Result<(), KanshiError> {
+        let mut watchers = self.watch_descriptors.lock().await;
+        unmark_path(&self.inotify, &mut watchers, file)?;
 
-                        if record.mask.contains(AddWatchFlags::IN_CREATE)
-                            && kind == FileSystemTargetKind::Directory
-                        {
-                            let absolute_path = path::absolute(Path::new(&full_path))?;
-                            mark(&self.inotify, &mut wd, absolute_path.as_path())?;
-                        }
+        let watches = self.sender.watches();
+        watches.remove(file);
+        if let Some(parent) = self.files.remove(file) {
+            if watches.owner(&parent).is_none() {
+                unmark_path(&self.inotify, &mut watchers, &parent)?;
+            }
+        }
 
-                        let tracer_event = FileSystemEvent {
-                            event_type,
-                            target: Some(FileSystemTarget {
-                                kind,
-                                path: full_path,
-                            }),
-                        };
+        Ok(())
+    }
 
-                        if let Err(_) = sender.send(tracer_event) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+    /// Called when a watched file's name starts or stops pointing at an inode, for
+    /// example when an editor renames a new version over it.
+    fn retarget_file(
+        &self,
+        watchers: &mut HashMap<WatchDescriptor, PathBuf>,
+        file: &Path,
+        arrived: bool,
+    ) -> Result<(), KanshiError> {
+        unmark_path(&self.inotify, watchers, file)?;
+        if arrived {
+            match mark(&self.inotify, watchers, file, file_flags(self.event_mask)) {
+                Ok(_) => {}
+                // It was already removed or replaced again. The next event will tell.
+                Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-                    // Is a MOVED_FROM or MOVED_TO event.
-                    } else if cookie_map.get(&record.cookie).is_none() {
-                        cookie_map.insert(record.cookie, record);
-                    } else {
-                        let other_record = cookie_map.remove(&record.cookie).unwrap();
-                        let mut wd = self.watch_descriptors.lock().await;
-                        let moved_from;
-                        let moved_to;
-
-                        let path = wd.get(&other_record.wd);
-                        let mut other_full_path = OsString::new();
-                        if let Some(path) = path {
-                            other_full_path.push(path.as_os_str());
-                        }
+        Ok(())
+    }
 
-                        other_full_path.push("/");
+    /// Marks the directories a changed ignore file at `path` no longer ignores.
+    async fn reload_ignore_files(&self, path: &Path) -> Result<(), KanshiError> {
+        for (dir, depth) in self.sender.filter().reload_ignore_files(path) {
+            let marked = self.mark_tree(&dir, depth).await?;
+            self.sender.watches().marked(&dir, marked);
+        }
+        Ok(())
+    }
 
-                        if let Some(name) = &other_record.name {
-                            other_full_path.push(name);
-                        }
+    /// Called when a symlink may have appeared at, disappeared from or been pointed
+    /// elsewhere at `link`. The directory it pointed to is unmarked, and the one it
+    /// points to now is marked unless that closes a cycle.
+    async fn relink(&self, link: &Path) -> Result<(), KanshiError> {
+        let watches = self.sender.watches();
+        let mut watchers = self.watch_descriptors.lock().await;
+        let mut unmarked = 0;
+        watchers.retain(|wd, path| {
+            let below = path.starts_with(link);
+            if below {
+                let _ = unmark(&self.inotify, wd, path);
+                unmarked += 1;
+            }
+            !below
+        });
+        drop(watchers);
+        watches.unmarked(link, unmarked);
+
+        let Ok(metadata) = fs::symlink_metadata(link) else {
+            return Ok(());
+        };
+        if !metadata.is_symlink() {
+            return Ok(());
+        }
+        let Some(target) = traversed_dir(link, metadata, true) else {
+            return Ok(());
+        };
+        let Some(depth) = watches
+            .depth(link)
+            .filter(|depth| self.recursion.includes(*depth))
+        else {
+            return Ok(());
+        };
+        if link
+            .parent()
+            .is_some_and(|parent| ancestor_ids(parent).contains(&dir_id(&target)))
+        {
+            return Ok(());
+        }
 
-                        let path = wd.get(&record.wd);
-                        let mut full_path = OsString::new();
-                        if let Some(path) = path {
-                            full_path.push(path.as_os_str());
-                        }
+        match self.mark_tree(link, depth).await {
+            Ok(marked) => watches.marked(link, marked),
+            // The link was removed or replaced again. The next event will tell.
+            Err(KanshiError::FileSystemError(e)) if e.kind == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
 
-                        full_path.push("/");
+    async fn read_events_loop(&self) -> Result<(), KanshiError> {
+        read_loop(self, &self.cancellation_token).await
+    }
+}
 
-                        if let Some(name) = &record.name {
-                            full_path.push(name);
-                        }
+/// Moves still waiting for their other half, and the metadata seen so far.
+#[derive(Default)]
+pub(crate) struct ReaderState {
+    cookie_map: HashMap<u32, (InotifyEvent, SystemTime)>,
+    metadata_cache: MetadataCache,
+}
 
-                        if other_record.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
-                            moved_from = Some(other_full_path);
-                            moved_to = Some(full_path);
-                        } else {
-                            moved_from = Some(full_path);
-                            moved_to = Some(other_full_path);
-                        }
+impl Reader for INotifyTracer {
+    type Records = Vec<InotifyEvent>;
+    type State = ReaderState;
 
-                        if kind == FileSystemTargetKind::Directory {
-                            let moved_from_as_path_buf =
-                                PathBuf::from(moved_from.as_ref().unwrap());
-                            let moved_to_as_path_buf = PathBuf::from(moved_to.as_ref().unwrap());
-                            for dir_path in wd.values_mut() {
-                                if dir_path.starts_with(&moved_from_as_path_buf) {
-                                    let relative_path =
-                                        dir_path.strip_prefix(&moved_from_as_path_buf);
-                                    if let Ok(relative_path) = relative_path {
-                                        let final_path =
-                                            moved_to_as_path_buf.join(relative_path).canonicalize();
-                                        if let Ok(final_path) = final_path {
-                                            *dir_path = final_path;
-                                        }
-                                    }
-                                }
-                            }
-                        }
+    fn sender(&self) -> &EventSender {
+        &self.sender
+    }
 
-                        let tracer_event1 = FileSystemEvent {
-                            event_type: FileSystemEventType::MovedTo(moved_to.clone().unwrap()),
-                            target: Some(FileSystemTarget {
-                                path: moved_from.clone().unwrap(),
-                                kind: kind.clone(),
-                            }),
-                        };
+    fn fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
 
-                        let tracer_event2 = FileSystemEvent {
-                            event_type: FileSystemEventType::MovedFrom(moved_from.unwrap()),
-                            target: Some(FileSystemTarget {
-                                path: moved_to.clone().unwrap(),
-                                kind,
-                            }),
-                        };
+    fn read_records(&self) -> io::Result<Vec<InotifyEvent>> {
+        self.inotify.read_events().map_err(io::Error::from)
+    }
 
-                        if let Err(_) = sender.send(tracer_event1) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+    async fn handle(
+        &self,
+        records: Vec<InotifyEvent>,
+        state: &mut ReaderState,
+    ) -> Result<(), KanshiError> {
+        let sender = &self.sender;
+        let timestamp = SystemTime::now();
+        for record in records {
+            // The kernel queue overflowed and events were dropped. Any pending
+            // cookies can no longer be paired reliably.
+            if record.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                state.cookie_map.clear();
+                if sender
+                    .send(FileSystemEvent::overflow(None, None, timestamp))
+                    .await
+                    .is_err()
+                {
+                    return Err(KanshiError::StreamClosedError);
+                }
+                continue;
+            }
+
+            let kind = if record.mask.contains(AddWatchFlags::IN_ISDIR) {
+                FileSystemTargetKind::Directory
+            } else {
+                FileSystemTargetKind::File
+            };
+
+            // Is a normal Inotify event
+            if record.cookie == 0 {
+                let mut wd = self.watch_descriptors.lock().await;
+
+                // Events can still be queued for watches that were already removed.
+                if record.mask.contains(AddWatchFlags::IN_IGNORED)
+                    || !wd.contains_key(&record.wd)
+                {
+                    continue;
+                }
 
-                        if let Err(_) = sender.send(tracer_event2) {
-                            return Err(KanshiError::StreamClosedError);
+                let full_path = record_path(&wd, &record);
+                if !self.files.wants(&full_path, sender.watches()) {
+                    continue;
+                }
+
+                let mut event_type = match record.mask {
+                    x if x.contains(AddWatchFlags::IN_CREATE) => {
+                        FileSystemEventType::Create
+                    }
+                    x if x.contains(AddWatchFlags::IN_DELETE) => {
+                        FileSystemEventType::Delete
+                    }
+                    x if x.contains(AddWatchFlags::IN_DELETE_SELF) => {
+                        FileSystemEventType::Delete
+                    }
+                    x if x.contains(AddWatchFlags::IN_MODIFY) => {
+                        FileSystemEventType::Modify
+                    }
+                    x if x.contains(AddWatchFlags::IN_CLOSE_WRITE) => {
+                        FileSystemEventType::CloseWrite
+                    }
+                    x if x.contains(AddWatchFlags::IN_CLOSE_NOWRITE) => {
+                        FileSystemEventType::CloseNoWrite
+                    }
+                    x if x.contains(AddWatchFlags::IN_OPEN) => FileSystemEventType::Open,
+                    x if x.contains(AddWatchFlags::IN_ACCESS) => {
+                        FileSystemEventType::Access
+                    }
+                    x if x.contains(AddWatchFlags::IN_ATTRIB) => {
+                        FileSystemEventType::Metadata(None)
+                    }
+                    _ => FileSystemEventType::Unknown,
+                };
+
+                if self.files.contains(Path::new(&full_path)) {
+                    match event_type {
+                        FileSystemEventType::Create => {
+                            self.retarget_file(&mut wd, Path::new(&full_path), true)?
                         }
+                        FileSystemEventType::Delete => {
+                            self.retarget_file(&mut wd, Path::new(&full_path), false)?
+                        }
+                        _ => {}
                     }
                 }
-            } else if !cookie_map.is_empty() {
-                // Assume all unfulfilled cookies as moves outside of watched directory.
-                for (_, record) in cookie_map.iter() {
-                    let mut wd = self.watch_descriptors.lock().await;
-                    let kind = if record.mask.contains(AddWatchFlags::IN_ISDIR) {
-                        FileSystemTargetKind::Directory
-                    } else {
-                        FileSystemTargetKind::File
-                    };
-                    let path = wd.get(&record.wd);
-                    let mut full_path = OsString::new();
-                    if let Some(path) = path {
-                        full_path.push(path.as_os_str());
+
+                if record.mask.contains(AddWatchFlags::IN_CREATE)
+                    && kind == FileSystemTargetKind::Directory
+                {
+                    let absolute_path =
+                        path::absolute(Path::new(&full_path)).map_err(|e| {
+                            KanshiError::file_system(
+                                FileSystemOperation::Traverse,
+                                Some(Path::new(&full_path)),
+                                e,
+                            )
+                        })?;
+                    let depth = sender.watches().depth(&absolute_path);
+                    if depth.is_some_and(|depth| {
+                        self.recursion.includes(depth)
+                            && !sender.filter().excludes(&absolute_path, depth, true)
+                    }) {
+                        mark(
+                            &self.inotify,
+                            &mut wd,
+                            absolute_path.as_path(),
+                            tree_flags(self.event_mask),
+                        )?;
+                        sender.watches().marked(&absolute_path, 1);
                     }
+                }
 
-                    full_path.push("/");
+                drop(wd);
+                if self.follow_symlinks
+                    && kind != FileSystemTargetKind::Directory
+                    && matches!(
+                        event_type,
+                        FileSystemEventType::Create | FileSystemEventType::Delete
+                    )
+                {
+                    self.relink(Path::new(&full_path)).await?;
+                }
+                self.reload_ignore_files(&full_path).await?;
 
-                    if let Some(name) = &record.name {
-                        full_path.push(name);
-                    }
+                let kind = kind.resolve(&full_path);
+                state.metadata_cache.update(&mut event_type, &full_path);
 
-                    let path_as_path_buf = PathBuf::from(full_path.clone());
+                let tracer_event = FileSystemEvent::new(
+                    event_type,
+                    Some(FileSystemTarget::new(full_path, kind)),
+                    timestamp,
+                );
 
-                    if kind == FileSystemTargetKind::Directory {
-                        if let Some(_) = wd
-                            .values()
-                            .find(|x| x.as_path() == path_as_path_buf.as_path())
-                        {
-                            wd.retain(|curr_wd, path| {
-                                if path.starts_with(&path_as_path_buf) {
-                                    let _ = unmark(&self.inotify, curr_wd);
-                                }
-                                !path.starts_with(&path_as_path_buf)
-                            });
-                            drop(wd);
-                        } else {
-                            drop(wd);
-                            self.watch(path_as_path_buf.clone().to_str().unwrap())
-                                .await?;
-                        }
-                    }
+                if let Err(_) = sender.send(tracer_event).await {
+                    return Err(KanshiError::StreamClosedError);
+                }
 
+            // Is a MOVED_FROM or MOVED_TO event.
+            } else if state.cookie_map.get(&record.cookie).is_none() {
+                state.cookie_map.insert(record.cookie, (record, timestamp));
+            } else {
+                let (other_record, _) = state.cookie_map.remove(&record.cookie).unwrap();
+                let mut wd = self.watch_descriptors.lock().await;
+                let moved_from;
+                let moved_to;
+
+                let other_full_path = record_path(&wd, &other_record);
+                let full_path = record_path(&wd, &record);
+
+                if !self.files.wants(Path::new(&other_full_path), sender.watches())
+                    && !self.files.wants(&full_path, sender.watches())
+                {
+                    continue;
+                }
 
-                    // if wd.contains_key(&record.wd) {
-                    //     wd.remove(&record.wd);
-                    // } else {
-                    //     wd.insert(record.wd, PathBuf::from(full_path.clone()));
-                    // }
+                if other_record.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                    moved_from = Some(other_full_path);
+                    moved_to = Some(full_path);
+                } else {
+                    moved_from = Some(full_path);
+                    moved_to = Some(other_full_path);
+                }
 
-                    let tracer_event = FileSystemEvent {
-                        event_type: FileSystemEventType::Move,
-                        target: Some(FileSystemTarget {
-                            path: full_path,
-                            kind,
-                        }),
-                    };
+                if kind == FileSystemTargetKind::Directory {
+                    let moved_from_as_path_buf =
+                        PathBuf::from(moved_from.as_ref().unwrap());
+                    let moved_to_as_path_buf = PathBuf::from(moved_to.as_ref().unwrap());
+                    for dir_path in wd.values_mut() {
+                        // Remapped as is. The tree may have moved on already, so it
+                        // can't be resolved on disk.
+                        let Ok(relative_path) = dir_path.strip_prefix(&moved_from_as_path_buf)
+                        else {
+                            continue;
+                        };
+                        *dir_path = if relative_path.as_os_str().is_empty() {
+                            moved_to_as_path_buf.clone()
+                        } else {
+                            moved_to_as_path_buf.join(relative_path)
+                        };
+                    }
+                }
 
-                    if let Err(_) = sender.send(tracer_event) {
+                let moved_from_path = Path::new(moved_from.as_ref().unwrap());
+                if self.files.contains(moved_from_path) {
+                    self.retarget_file(&mut wd, moved_from_path, false)?;
+                }
+                let moved_to_path = Path::new(moved_to.as_ref().unwrap());
+                if self.files.contains(moved_to_path) {
+                    self.retarget_file(&mut wd, moved_to_path, true)?;
+                }
+                drop(wd);
+                if self.follow_symlinks && kind != FileSystemTargetKind::Directory {
+                    self.relink(moved_from_path).await?;
+                    self.relink(moved_to_path).await?;
+                }
+                self.reload_ignore_files(moved_from_path).await?;
+                self.reload_ignore_files(moved_to_path).await?;
+
+                let moved_to = moved_to.unwrap();
+                let kind = kind.resolve(&moved_to);
+                let tracer_events = FileSystemEvent::rename(
+                    moved_from.unwrap(),
+                    moved_to,
+                    kind,
+                    timestamp,
+                    self.split_renames,
+                );
+
+                for tracer_event in tracer_events {
+                    if sender.send(tracer_event).await.is_err() {
                         return Err(KanshiError::StreamClosedError);
                     }
                 }
-                cookie_map.clear();
             }
         }
-
         Ok(())
     }
 
-    fn close(&self) -> bool {
-        if self.cancellation_token.is_cancelled() {
-            return true;
-        }
-
-        self.cancellation_token.cancel();
+    // Only wait for the other half of a move for so long.
+    fn window(&self, state: &ReaderState) -> Option<Duration> {
+        (!state.cookie_map.is_empty()).then_some(MOVE_PAIRING_WINDOW)
+    }
 
-        let mut has_error = false;
+    async fn flush(&self, state: &mut ReaderState) -> Result<(), KanshiError> {
+        let sender = &self.sender;
+        // Assume all unfulfilled cookies as moves outside of watched directory.
+        for (_, (record, timestamp)) in state.cookie_map.iter() {
+            let mut wd = self.watch_descriptors.lock().await;
+            let kind = if record.mask.contains(AddWatchFlags::IN_ISDIR) {
+                FileSystemTargetKind::Directory
+            } else {
+                FileSystemTargetKind::File
+            };
+            let full_path = record_path(&wd, record);
+            if !self.files.wants(&full_path, sender.watches()) {
+                continue;
+            }
 
-        if self.epoll.delete(self.inotify.as_fd()).is_err() {
-            eprintln!("epoll.delete returned error");
-            has_error = true;
-        }
+            let path_as_path_buf = full_path.clone();
+            if self.files.contains(&path_as_path_buf) {
+                let arrived = record.mask.contains(AddWatchFlags::IN_MOVED_TO);
+                self.retarget_file(&mut wd, &path_as_path_buf, arrived)?;
+            }
 
-        // Inotify is automatically closed on drop.
+            if kind == FileSystemTargetKind::Directory {
+                if let Some(_) = wd
+                    .values()
+                    .find(|x| x.as_path() == path_as_path_buf.as_path())
+                {
+                    let mut unmarked = 0;
+                    wd.retain(|curr_wd, path| {
+                        if path.starts_with(&path_as_path_buf) {
+                            let _ = unmark(&self.inotify, curr_wd, path);
+                            unmarked += 1;
+                        }
+                        !path.starts_with(&path_as_path_buf)
+                    });
+                    drop(wd);
+                    sender.watches().unmarked(&path_as_path_buf, unmarked);
+                } else if let Some(depth) = sender
+                    .watches()
+                    .depth(&path_as_path_buf)
+                    .filter(|depth| self.recursion.includes(*depth))
+                {
+                    drop(wd);
+                    let marked = self.mark_tree(&path_as_path_buf, depth).await?;
+                    sender.watches().marked(&path_as_path_buf, marked);
+                }
+            } else {
+                drop(wd);
+                if self.follow_symlinks {
+                    self.relink(&path_as_path_buf).await?;
+                }
+                self.reload_ignore_files(&path_as_path_buf).await?;
+            }
 
-        !has_error
+            let direction = if record.mask.contains(AddWatchFlags::IN_MOVED_TO) {
+                MoveDirection::In
+            } else {
+                MoveDirection::Out
+            };
+            let kind = kind.resolve(&full_path);
+            let tracer_event = FileSystemEvent::moved(
+                direction,
+                FileSystemTarget::new(full_path, kind),
+                *timestamp,
+            );
+
+            if let Err(_) = sender.send(tracer_event).await {
+                return Err(KanshiError::StreamClosedError);
+            }
+        }
+        state.cookie_map.clear();
+        Ok(())
     }
 }
 
+/// How long a `IN_MOVED_FROM` or `IN_MOVED_TO` waits for its other half before it is
+/// reported as a move in or out of the watched tree.
+const MOVE_PAIRING_WINDOW: Duration = Duration::from_millis(16);
+
+/// Directory entry events are all a watched file's directory has to report.
+const FILE_PARENT_FLAGS: AddWatchFlags = AddWatchFlags::IN_CREATE
+    .union(AddWatchFlags::IN_MOVE)
+    .union(AddWatchFlags::IN_DELETE);
+
 fn mark(
     inotify: &Inotify,
     watchers: &mut HashMap<WatchDescriptor, PathBuf>,
     path: &Path,
-) -> Result<(), KanshiError> {
-    use nix::sys::inotify::AddWatchFlags;
-    #[allow(non_snake_case)]
-    let MASK_FLAGS = AddWatchFlags::IN_CREATE
-        | AddWatchFlags::IN_MODIFY
-        | AddWatchFlags::IN_MOVE
-        | AddWatchFlags::IN_DELETE;
-
-    let wd = inotify.add_watch(path, MASK_FLAGS);
+    mask_flags: AddWatchFlags,
+) -> Result<WatchDescriptor, KanshiError> {
+    let wd = inotify.add_watch(path, mask_flags);
     if let Err(e) = wd {
-        Err(KanshiError::FileSystemError(e.to_string()))
+        Err(KanshiError::file_system(
+            FileSystemOperation::Mark,
+            Some(path),
+            e,
+        ))
     } else {
         let wd = wd.ok().unwrap();
         watchers.insert(wd, path.to_path_buf());
-        Ok(())
+        Ok(wd)
+    }
+}
+
+/// Same as `mark()`, but records the descriptor in `added` if `path` was not marked yet.
+fn mark_new(
+    inotify: &Inotify,
+    watchers: &mut HashMap<WatchDescriptor, PathBuf>,
+    path: &Path,
+    mask_flags: AddWatchFlags,
+    added: &mut Vec<WatchDescriptor>,
+) -> Result<(), KanshiError> {
+    let marked = watchers.len();
+    let wd = mark(inotify, watchers, path, mask_flags)?;
+    if watchers.len() > marked {
+        added.push(wd);
+    }
+    Ok(())
+}
+
+/// Removes the descriptors added by a `mark_tree()` or `watch_file()` that failed.
+fn unmark_added(
+    inotify: &Inotify,
+    watchers: &mut HashMap<WatchDescriptor, PathBuf>,
+    added: Vec<WatchDescriptor>,
+) {
+    for wd in added {
+        if let Some(path) = watchers.remove(&wd) {
+            // The error that failed the caller is the one reported.
+            let _ = unmark(inotify, &wd, &path);
+        }
+    }
+}
+
+/// Flags for the directories of a watched tree.
+fn tree_flags(event_mask: EventMask) -> AddWatchFlags {
+    // Creates and moves are always needed to keep new and renamed directories watched.
+    // They are dropped by the sender if they were not selected.
+    let mut mask_flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVE;
+
+    if event_mask.contains(EventMask::DELETE) {
+        mask_flags |= AddWatchFlags::IN_DELETE;
+    }
+
+    mask_flags | file_flags(event_mask)
+}
+
+/// Flags for a file watched on its own. Creates, deletes and renames of the file
+/// are reported by its directory.
+fn file_flags(event_mask: EventMask) -> AddWatchFlags {
+    let mut mask_flags = AddWatchFlags::empty();
+
+    for (class, flags) in [
+        (EventMask::MODIFY, AddWatchFlags::IN_MODIFY),
+        (EventMask::CLOSE_WRITE, AddWatchFlags::IN_CLOSE_WRITE),
+        (EventMask::CLOSE_NOWRITE, AddWatchFlags::IN_CLOSE_NOWRITE),
+        (EventMask::OPEN, AddWatchFlags::IN_OPEN),
+        (EventMask::ACCESS, AddWatchFlags::IN_ACCESS),
+        (EventMask::ATTRIB, AddWatchFlags::IN_ATTRIB),
+    ] {
+        if event_mask.contains(class) {
+            mask_flags |= flags;
+        }
+    }
+
+    mask_flags
+}
+
+/// Path of the item an event is about. Events about a marked item itself have no name.
+fn record_path(watchers: &HashMap<WatchDescriptor, PathBuf>, record: &InotifyEvent) -> PathBuf {
+    let path = watchers.get(&record.wd).cloned().unwrap_or_default();
+    match &record.name {
+        Some(name) => path.join(name),
+        None => path,
     }
 }
 
-fn unmark(inotify: &Inotify, wd: &WatchDescriptor) -> Result<(), KanshiError> {
-    inotify.rm_watch(*wd)?;
+/// Removes every watch on exactly `path`.
+fn unmark_path(
+    inotify: &Inotify,
+    watchers: &mut HashMap<WatchDescriptor, PathBuf>,
+    path: &Path,
+) -> Result<(), KanshiError> {
+    let descriptors: Vec<WatchDescriptor> = watchers
+        .iter()
+        .filter(|(_, watched)| watched.as_path() == path)
+        .map(|(wd, _)| *wd)
+        .collect();
+
+    for wd in descriptors {
+        watchers.remove(&wd);
+        unmark(inotify, &wd, path)?;
+    }
+
     Ok(())
 }
+
+fn unmark(inotify: &Inotify, wd: &WatchDescriptor, path: &Path) -> Result<(), KanshiError> {
+    match inotify.rm_watch(*wd) {
+        // The kernel already removed the watch, usually because the directory was deleted.
+        Ok(_) | Err(Errno::EINVAL) => Ok(()),
+        Err(e) => Err(KanshiError::file_system(
+            FileSystemOperation::Unmark,
+            Some(path),
+            e,
+        )),
+    }
+}
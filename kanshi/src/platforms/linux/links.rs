@@ -0,0 +1,123 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::watches::WatchRegistry;
+
+/// Identifies a directory across the links leading to it.
+pub(crate) type DirId = (u64, u64);
+
+pub(crate) fn dir_id(metadata: &Metadata) -> DirId {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Ids of `path` and every directory above it. A link to one of them is a cycle.
+pub(crate) fn ancestor_ids(path: &Path) -> HashSet<DirId> {
+    path.ancestors()
+        .filter_map(|ancestor| fs::metadata(ancestor).ok())
+        .map(|metadata| dir_id(&metadata))
+        .collect()
+}
+
+/// Metadata of the directory an entry leads to, if traversal should descend into it.
+/// `metadata` is the entry's own metadata, so symlinks are only resolved if `follow` is set.
+pub(crate) fn traversed_dir(path: &Path, metadata: Metadata, follow: bool) -> Option<Metadata> {
+    if metadata.is_symlink() {
+        if !follow {
+            return None;
+        }
+        fs::metadata(path).ok().filter(|metadata| metadata.is_dir())
+    } else {
+        Some(metadata).filter(|metadata| metadata.is_dir())
+    }
+}
+
+/// Symlinked directories followed into. Fanotify resolves every event to the real
+/// path of its target, so events below a target are reported under the link again.
+#[derive(Clone, Default)]
+pub(crate) struct FollowedLinks {
+    // Canonical target, by link path.
+    targets: Arc<Mutex<HashMap<PathBuf, PathBuf>>>,
+}
+
+impl FollowedLinks {
+    pub(crate) fn add(&self, link: PathBuf, target: PathBuf) {
+        self.targets.lock().unwrap().insert(link, target);
+    }
+
+    /// Forgets the links followed below `root` that no watch contains anymore.
+    pub(crate) fn remove_unwatched(&self, root: &Path, watches: &WatchRegistry) {
+        let mut targets = self.targets.lock().unwrap();
+        targets.retain(|link, _| !link.starts_with(root) || watches.owner(link).is_some());
+    }
+
+    /// Forgets the link at `link` and the links followed below it. Returns the target
+    /// `link` pointed to.
+    pub(crate) fn remove(&self, link: &Path) -> Option<PathBuf> {
+        let mut targets = self.targets.lock().unwrap();
+        let target = targets.remove(link);
+        targets.retain(|path, _| !path.starts_with(link));
+        target
+    }
+
+    /// Maps a path below a followed target back to the path below its link.
+    pub(crate) fn rewrite(&self, path: PathBuf) -> PathBuf {
+        let targets = self.targets.lock().unwrap();
+        let relative = targets
+            .iter()
+            .filter_map(|(link, target)| {
+                path.strip_prefix(target)
+                    .ok()
+                    .map(|relative| (link, target, relative))
+            })
+            .max_by_key(|(_, target, _)| target.components().count());
+
+        match relative {
+            Some((link, _, relative)) if relative.as_os_str().is_empty() => link.clone(),
+            Some((link, _, relative)) => link.join(relative),
+            None => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::FollowedLinks;
+
+    #[test]
+    fn rewrites_deepest_target() {
+        let links = FollowedLinks::default();
+        links.add(
+            PathBuf::from("/w/node_modules/a"),
+            PathBuf::from("/store/a"),
+        );
+        links.add(
+            PathBuf::from("/w/node_modules/a/lib"),
+            PathBuf::from("/store/lib"),
+        );
+
+        let rewrite = |path: &str| links.rewrite(PathBuf::from(path));
+        assert_eq!(
+            rewrite("/store/a/index.js"),
+            Path::new("/w/node_modules/a/index.js")
+        );
+        assert_eq!(
+            rewrite("/store/lib/x.js"),
+            Path::new("/w/node_modules/a/lib/x.js")
+        );
+        assert_eq!(rewrite("/store/a"), Path::new("/w/node_modules/a"));
+        assert_eq!(rewrite("/elsewhere/x.js"), Path::new("/elsewhere/x.js"));
+
+        assert_eq!(
+            links.remove(PathBuf::from("/w/node_modules/a").as_path()),
+            Some(PathBuf::from("/store/a"))
+        );
+        assert_eq!(rewrite("/store/lib/x.js"), Path::new("/store/lib/x.js"));
+    }
+}
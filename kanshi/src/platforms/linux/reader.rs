@@ -0,0 +1,142 @@
+use std::{
+    io,
+    os::fd::{AsFd, BorrowedFd},
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+};
+use tokio::io::{unix::AsyncFd, Interest};
+use tokio_util::sync::CancellationToken;
+
+use crate::{channel::EventSender, FileSystemOperation, KanshiError};
+
+/// An engine's side of reading its notification fd. `start()` waits for the fd on
+/// the runtime's reactor, a `blocking::Watcher` polls it on the caller's thread.
+pub(crate) trait Reader {
+    type Records;
+    /// Carried from one batch of records to the next.
+    type State: Default;
+
+    fn sender(&self) -> &EventSender;
+
+    fn fd(&self) -> BorrowedFd<'_>;
+
+    /// Reads the queued records. Fails with `WouldBlock` if there are none.
+    fn read_records(&self) -> io::Result<Self::Records>;
+
+    async fn handle(
+        &self,
+        records: Self::Records,
+        state: &mut Self::State,
+    ) -> Result<(), KanshiError>;
+
+    /// How long to wait for more records before `flush` has to run, if the state
+    /// is holding on to any.
+    fn window(&self, _state: &Self::State) -> Option<Duration> {
+        None
+    }
+
+    /// Reports what the state held on to, as no more records came in time.
+    async fn flush(&self, _state: &mut Self::State) -> Result<(), KanshiError> {
+        Ok(())
+    }
+}
+
+/// Reads and handles records until `cancel_token` is cancelled.
+pub(crate) async fn read_loop<R: Reader>(
+    reader: &R,
+    cancel_token: &CancellationToken,
+) -> Result<(), KanshiError> {
+    let fd = AsyncFd::with_interest(reader.fd(), Interest::READABLE)
+        .map_err(|e| KanshiError::file_system(FileSystemOperation::EpollWait, None, e))?;
+    let mut state = R::State::default();
+
+    // Marks are placed as soon as watch() returns, so anything from here on is read.
+    reader.sender().listening();
+    while !cancel_token.is_cancelled() {
+        // Without anything held back, sleep until there is something to read.
+        let window = reader.window(&state);
+        let readable = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            guard = fd.readable(), if window.is_none() => Some(guard),
+            guard = tokio::time::timeout(window.unwrap_or_default(), fd.readable()),
+                if window.is_some() => guard.ok(),
+        };
+
+        let Some(guard) = readable else {
+            reader.flush(&mut state).await?;
+            continue;
+        };
+        let mut guard =
+            guard.map_err(|e| KanshiError::file_system(FileSystemOperation::EpollWait, None, e))?;
+        // A spurious wakeup clears the readiness, so the next wait sleeps again.
+        let Ok(records) = guard.try_io(|_| reader.read_records()) else {
+            continue;
+        };
+        let records = records
+            .map_err(|e| KanshiError::file_system(FileSystemOperation::ReadEvents, None, e))?;
+        reader.handle(records, &mut state).await?;
+    }
+
+    Ok(())
+}
+
+/// Waits up to `timeout`, or forever if it is `None`, for records and handles them.
+/// Returns early without reading anything if `wake` becomes readable.
+pub(crate) fn read_blocking<R: Reader>(
+    reader: &R,
+    state: &mut R::State,
+    timeout: Option<Duration>,
+    wake: BorrowedFd<'_>,
+) -> Result<(), KanshiError> {
+    let window = reader.window(state);
+    let wait = match (timeout, window) {
+        (Some(timeout), Some(window)) => Some(timeout.min(window)),
+        (timeout, window) => timeout.or(window),
+    };
+    let poll_timeout = wait.map_or(PollTimeout::NONE, |wait| {
+        PollTimeout::try_from(wait).unwrap_or(PollTimeout::MAX)
+    });
+
+    let mut fds = [
+        PollFd::new(reader.fd(), PollFlags::POLLIN),
+        PollFd::new(wake.as_fd(), PollFlags::POLLIN),
+    ];
+    let ready = match poll(&mut fds, poll_timeout) {
+        Ok(ready) => ready,
+        // The caller works out how much of its timeout is left and asks again.
+        Err(Errno::EINTR) => return Ok(()),
+        Err(e) => {
+            return Err(KanshiError::file_system(
+                FileSystemOperation::EpollWait,
+                None,
+                e,
+            ))
+        }
+    };
+
+    if ready == 0 {
+        // Only flush once nothing came for the whole window, not because the
+        // caller's timeout was shorter.
+        if window.is_some() && wait == window {
+            futures::executor::block_on(reader.flush(state))?;
+        }
+        return Ok(());
+    }
+    if fds[1].any().unwrap_or(false) {
+        return Ok(());
+    }
+
+    match reader.read_records() {
+        Ok(records) => futures::executor::block_on(reader.handle(records, state)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(KanshiError::file_system(
+            FileSystemOperation::ReadEvents,
+            None,
+            e,
+        )),
+    }
+}
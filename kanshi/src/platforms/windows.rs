@@ -4,9 +4,14 @@
 
 // pub use readdirectorychangesw::*;
 
-use crate::KanshiError;
+use std::{fmt, time::Duration};
 
-#[derive(Clone)]
+use crate::{
+  BackpressurePolicy, EventMask, KanshiError, RecursionMode, RootPaths,
+  DEFAULT_EVENT_BUFFER_CAPACITY,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KanshiEngines {
     ReadDirectoryChangesW
 }
@@ -22,7 +27,45 @@ impl KanshiEngines {
     }
 }
 
+impl fmt::Display for KanshiEngines {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KanshiEngines::ReadDirectoryChangesW => f.write_str("readdirectorychangesw"),
+        }
+    }
+}
+
 pub struct KanshiOptions {
   pub force_engine: Option<KanshiEngines>,
+  pub event_buffer_capacity: usize,
+  pub backpressure: BackpressurePolicy,
+  pub split_renames: bool,
+  pub event_mask: EventMask,
+  pub recursion: RecursionMode,
+  pub follow_symlinks: bool,
+  pub root_paths: RootPaths,
+  pub include: Vec<String>,
+  pub exclude: Vec<String>,
+  pub respect_ignore_files: bool,
+  pub atomic_saves: Option<Duration>,
+}
+
+impl Default for KanshiOptions {
+  fn default() -> Self {
+    KanshiOptions {
+      force_engine: None,
+      event_buffer_capacity: DEFAULT_EVENT_BUFFER_CAPACITY,
+      backpressure: BackpressurePolicy::default(),
+      split_renames: false,
+      event_mask: EventMask::default(),
+      recursion: RecursionMode::default(),
+      follow_symlinks: false,
+      root_paths: RootPaths::default(),
+      include: Vec::new(),
+      exclude: Vec::new(),
+      respect_ignore_files: false,
+      atomic_saves: None,
+    }
+  }
 }
 
@@ -0,0 +1,241 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{KanshiEngines, RootPaths, WatchHandle};
+
+/// Keeps the `WatchHandle` of every tree passed to `watch()`. Engines report the
+/// directories they mark and unmark here, and the sender uses it to tell which
+/// watch an event belongs to.
+#[derive(Clone, Default)]
+pub(crate) struct WatchRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    last_id: u64,
+    root_paths: RootPaths,
+    watches: Vec<Watch>,
+}
+
+struct Watch {
+    handle: WatchHandle,
+    // Engines that resolve paths through the kernel report them canonicalized,
+    // so events are matched against both forms of the root.
+    requested_root: PathBuf,
+    canonical_root: PathBuf,
+}
+
+/// Where an event path lies, as reported to subscribers.
+pub(crate) struct Located {
+    pub(crate) watch_id: u64,
+    /// The path below the root as the root path policy reports it.
+    pub(crate) path: PathBuf,
+    /// The path below the canonical root, which ignore rules are matched against.
+    pub(crate) canonical_path: PathBuf,
+    pub(crate) relative_path: PathBuf,
+}
+
+impl Watch {
+    /// Number of path components of the root `path` is below, if it is below it.
+    fn depth(&self, path: &Path) -> Option<usize> {
+        self.relative(path).map(|(depth, _)| depth)
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> Option<(usize, &'a Path)> {
+        [&self.requested_root, &self.canonical_root]
+            .into_iter()
+            .find_map(|root| {
+                let relative = path.strip_prefix(root).ok()?;
+                Some((root.components().count(), relative))
+            })
+    }
+
+    fn is_rooted_at(&self, path: &Path) -> bool {
+        self.requested_root == path || self.canonical_root == path
+    }
+}
+
+impl Registry {
+    /// The watch with the deepest root containing `path`.
+    fn owner(&mut self, path: &Path) -> Option<&mut Watch> {
+        self.watches
+            .iter_mut()
+            .filter_map(|watch| watch.depth(path).map(|depth| (depth, watch)))
+            .max_by_key(|(depth, _)| *depth)
+            .map(|(_, watch)| watch)
+    }
+}
+
+impl WatchRegistry {
+    pub(crate) fn new(root_paths: RootPaths) -> WatchRegistry {
+        let registry = Registry {
+            root_paths,
+            ..Default::default()
+        };
+        WatchRegistry {
+            inner: Arc::new(Mutex::new(registry)),
+        }
+    }
+
+    /// Registers a watch on `root`, which is absolute but not canonicalized.
+    pub(crate) fn add(
+        &self,
+        root: PathBuf,
+        directories: usize,
+        engine: KanshiEngines,
+    ) -> WatchHandle {
+        let canonical_root = root.canonicalize().unwrap_or_else(|_| root.clone());
+        let mut registry = self.inner.lock().unwrap();
+        registry.last_id += 1;
+
+        let handle = WatchHandle {
+            id: registry.last_id,
+            root: match registry.root_paths {
+                RootPaths::AsRequested => root.clone(),
+                RootPaths::Canonical => canonical_root.clone(),
+            },
+            directories,
+            engine,
+        };
+        registry.watches.push(Watch {
+            handle: handle.clone(),
+            requested_root: root,
+            canonical_root,
+        });
+        handle
+    }
+
+    /// Forgets the watch rooted at `path`. Watches nested in it are kept. Returns
+    /// false if there is none.
+    pub(crate) fn remove(&self, path: &Path) -> bool {
+        let mut registry = self.inner.lock().unwrap();
+        let watched = registry.watches.len();
+        registry.watches.retain(|watch| !watch.is_rooted_at(path));
+        registry.watches.len() != watched
+    }
+
+    /// Counts `count` directories at or below `path` marked after their watch was added.
+    pub(crate) fn marked(&self, path: &Path, count: usize) {
+        let mut registry = self.inner.lock().unwrap();
+        if let Some(watch) = registry.owner(path) {
+            watch.handle.directories += count;
+        }
+    }
+
+    /// Takes `count` directories at or below `path` off the watch containing it.
+    pub(crate) fn unmarked(&self, path: &Path, count: usize) {
+        let mut registry = self.inner.lock().unwrap();
+        if let Some(watch) = registry.owner(path) {
+            watch.handle.directories = watch.handle.directories.saturating_sub(count);
+        }
+    }
+
+    /// Number of levels `path` is below the root of the watch it belongs to.
+    pub(crate) fn depth(&self, path: &Path) -> Option<usize> {
+        let mut registry = self.inner.lock().unwrap();
+        let watch = registry.owner(path)?;
+        let root_depth = watch.depth(path)?;
+        Some(path.components().count() - root_depth)
+    }
+
+    /// The watch `path` belongs to, and `path` rewritten to start with its reported root.
+    pub(crate) fn locate(&self, path: &Path) -> Option<Located> {
+        let mut registry = self.inner.lock().unwrap();
+        let watch = registry.owner(path)?;
+        let (_, relative_path) = watch.relative(path)?;
+
+        let below = |root: &Path| {
+            if relative_path.as_os_str().is_empty() {
+                root.to_path_buf()
+            } else {
+                root.join(relative_path)
+            }
+        };
+        Some(Located {
+            watch_id: watch.handle.id,
+            path: below(&watch.handle.root),
+            canonical_path: below(&watch.canonical_root),
+            relative_path: relative_path.to_path_buf(),
+        })
+    }
+
+    /// Id of the watch `path` belongs to.
+    pub(crate) fn owner(&self, path: &Path) -> Option<u64> {
+        let mut registry = self.inner.lock().unwrap();
+        registry.owner(path).map(|watch| watch.handle.id)
+    }
+
+    pub(crate) fn list(&self) -> Vec<WatchHandle> {
+        let registry = self.inner.lock().unwrap();
+        registry
+            .watches
+            .iter()
+            .map(|watch| watch.handle.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::WatchRegistry;
+    use crate::{KanshiEngines, RootPaths};
+
+    #[test]
+    fn events_belong_to_deepest_watch() {
+        let registry = WatchRegistry::default();
+        let outer = registry.add(PathBuf::from("/kanshi/a"), 1, KanshiEngines::Inotify);
+        let inner = registry.add(PathBuf::from("/kanshi/a/b"), 1, KanshiEngines::Inotify);
+
+        assert_eq!(registry.owner(Path::new("/kanshi/a/file")), Some(outer.id));
+        assert_eq!(
+            registry.owner(Path::new("/kanshi/a/b/c/file")),
+            Some(inner.id)
+        );
+        assert_eq!(registry.owner(Path::new("/kanshi/ab")), None);
+
+        assert_eq!(registry.depth(Path::new("/kanshi/a/b/c/file")), Some(2));
+
+        registry.marked(Path::new("/kanshi/a/b/c"), 1);
+        assert_eq!(registry.list()[1].directories, 2);
+
+        assert!(registry.remove(Path::new("/kanshi/a/b")));
+        assert!(!registry.remove(Path::new("/kanshi/a/b")));
+        assert_eq!(registry.list(), vec![outer.clone()]);
+        assert_eq!(
+            registry.owner(Path::new("/kanshi/a/b/c/file")),
+            Some(outer.id)
+        );
+    }
+
+    #[test]
+    fn locates_paths_below_requested_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().to_path_buf();
+        let real = dir.join("real");
+        let link = dir.join("link");
+        std::fs::create_dir_all(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let real = real.canonicalize().unwrap();
+
+        let registry = WatchRegistry::new(RootPaths::AsRequested);
+        let handle = registry.add(link.clone(), 1, KanshiEngines::Inotify);
+        assert_eq!(handle.root, link);
+        let located = registry.locate(&real.join("a/file")).unwrap();
+        assert_eq!(located.watch_id, handle.id);
+        assert_eq!(located.path, link.join("a/file"));
+        assert_eq!(located.relative_path, Path::new("a/file"));
+
+        let registry = WatchRegistry::new(RootPaths::Canonical);
+        let handle = registry.add(link.clone(), 1, KanshiEngines::Inotify);
+        assert_eq!(handle.root, real);
+        let located = registry.locate(&link.join("a/file")).unwrap();
+        assert_eq!(located.path, real.join("a/file"));
+        assert_eq!(located.relative_path, Path::new("a/file"));
+    }
+}